@@ -0,0 +1,93 @@
+//! Bit-level reading and writing, used by the generated code of bit-level formats.
+
+use std::io::{Read, Result, Write};
+
+/// A writer of values that take any number of bits, which packs them into bytes.
+///
+/// With MSB-first order, bits fill each byte from its most significant bit, and each value is
+/// written from its most significant bit. With LSB-first order, both go from the least significant bit.
+pub struct BitWriter<W> {
+    inner: W,
+    lsb_first: bool,
+    /// The bits of the current byte
+    byte: u8,
+    /// The number of bits in the current byte
+    length: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    pub fn new(inner: W, lsb_first: bool) -> BitWriter<W> {
+        BitWriter { inner, lsb_first, byte: 0, length: 0 }
+    }
+
+    /// Write the low `width` bits of `value` (`width` is at most 64)
+    pub fn write_bits(&mut self, value: u64, width: u32) -> Result<()> {
+        for i in 0..width {
+            let bit_index = if self.lsb_first { i } else { width - 1 - i };
+            let bit = (value >> bit_index) as u8 & 1;
+            if self.lsb_first {
+                self.byte |= bit << self.length;
+            } else {
+                self.byte |= bit << (7 - self.length);
+            }
+            self.length += 1;
+            if self.length == 8 {
+                self.inner.write_all(&[self.byte])?;
+                self.byte = 0;
+                self.length = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the last byte, if it is partial, with zeros in the rest of its bits
+    pub fn finish(mut self) -> Result<()> {
+        if self.length != 0 {
+            self.inner.write_all(&[self.byte])?;
+            self.length = 0;
+        }
+        Ok(())
+    }
+}
+
+/// A reader of values that take any number of bits, in the same bit orders as `BitWriter`.
+pub struct BitReader<R> {
+    inner: R,
+    lsb_first: bool,
+    /// The current byte
+    byte: u8,
+    /// The number of bits of the current byte that are not read yet
+    remaining: u32,
+}
+
+impl<R: Read> BitReader<R> {
+    pub fn new(inner: R, lsb_first: bool) -> BitReader<R> {
+        BitReader { inner, lsb_first, byte: 0, remaining: 0 }
+    }
+
+    /// Read a value of `width` bits (`width` is at most 64)
+    pub fn read_bits(&mut self, width: u32) -> Result<u64> {
+        let mut value = 0;
+        for i in 0..width {
+            if self.remaining == 0 {
+                let mut buf = [0];
+                self.inner.read_exact(&mut buf)?;
+                self.byte = buf[0];
+                self.remaining = 8;
+            }
+            let position = 8 - self.remaining;
+            let bit = if self.lsb_first {
+                self.byte >> position & 1
+            } else {
+                self.byte >> (7 - position) & 1
+            };
+            self.remaining -= 1;
+            if self.lsb_first {
+                value |= u64::from(bit) << i;
+            } else {
+                value = value << 1 | u64::from(bit);
+            }
+        }
+        Ok(value)
+    }
+}
@@ -0,0 +1,77 @@
+//! Checksum algorithms, used by the '#' format character.
+
+use byteorder::ByteOrder;
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn crc16_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32_TABLE: [u32; 256] = crc32_table();
+static CRC16_TABLE: [u16; 256] = crc16_table();
+
+/// CRC-32 (ISO-HDLC), as used by PNG, zlib and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &b| (crc >> 8) ^ CRC32_TABLE[((crc ^ u32::from(b)) & 0xff) as usize])
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffffu16, |crc, &b| (crc << 8) ^ CRC16_TABLE[((crc >> 8) ^ u16::from(b)) as usize])
+}
+
+/// The Internet checksum (RFC 1071): the ones' complement of the ones' complement sum of 16-bit
+/// words. The words are read in the byte order `E` that the checksum is written in, so it can be
+/// verified in either byte order.
+pub fn internet<E: ByteOrder>(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    let mut words = data.chunks(2);
+    for word in &mut words {
+        let word = if word.len() == 2 { E::read_u16(word) } else { E::read_u16(&[word[0], 0]) };
+        sum += u32::from(word);
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// XOR of all the bytes
+pub fn xor8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |x, &b| x ^ b)
+}
+
+/// Zero the bytes of the field at `offset` (of `width` bytes) that are in `covered`, which starts at
+/// `start`. Checksums are computed while their own field (and any later checksum field) is zero.
+pub fn clear_field(covered: &mut [u8], start: usize, offset: usize, width: usize) {
+    let from = offset.max(start).min(start + covered.len());
+    let to = (offset + width).max(start).min(start + covered.len());
+    for b in &mut covered[from - start..to - start] {
+        *b = 0;
+    }
+}
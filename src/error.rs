@@ -0,0 +1,134 @@
+//! Errors found in the data while unpacking.
+//!
+//! They are returned inside an `std::io::Error` of kind `ErrorKind::InvalidData`, and can be
+//! inspected with `get_ref()` and `downcast_ref()`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn main() {
+//! use structure::error::{Literal, LiteralMismatch};
+//!
+//! let err = structure!("B H=0xCAFE").unpack(&[1, 0xBA, 0xBE]).unwrap_err();
+//! let mismatch = err.get_ref().unwrap().downcast_ref::<LiteralMismatch>().unwrap();
+//! assert_eq!(mismatch.offset, 1);
+//! assert_eq!(mismatch.expected, Literal::Unsigned(0xCAFE));
+//! assert_eq!(mismatch.actual, Literal::Unsigned(0xBABE));
+//! # }
+//! ```
+
+use std::error::Error;
+use std::fmt;
+
+/// The value of a literal field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Literal {
+    Unsigned(u128),
+    Signed(i128),
+    Bytes(Vec<u8>),
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Literal::Unsigned(n) => write!(f, "{:#x}", n),
+            Literal::Signed(n) => write!(f, "{}", n),
+            Literal::Bytes(ref bytes) => {
+                write!(f, "'")?;
+                for &b in bytes {
+                    write!(f, "{}", std::ascii::escape_default(b))?;
+                }
+                write!(f, "'")
+            }
+        }
+    }
+}
+
+/// A literal field (like the magic number in "I=0xCAFEBABE") does not have the value from the format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiteralMismatch {
+    /// The offset of the field from where unpacking started
+    pub offset: u64,
+    pub expected: Literal,
+    pub actual: Literal,
+}
+
+impl fmt::Display for LiteralMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Literal field does not match the format at offset {} (expected: {}, actual: {})",
+               self.offset, self.expected, self.actual)
+    }
+}
+
+impl Error for LiteralMismatch {}
+
+/// A checksum algorithm of the '#' format character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// CRC-32 (ISO-HDLC), as used by PNG, zlib and Ethernet
+    Crc32,
+    /// CRC-16/CCITT-FALSE
+    Crc16,
+    /// The Internet checksum (RFC 1071)
+    Internet,
+    /// XOR of all the bytes
+    Xor8,
+}
+
+/// A checksum field does not match the checksum of the bytes it covers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// The offset of the checksum field from where unpacking started
+    pub offset: u64,
+    pub algorithm: ChecksumAlgorithm,
+    /// The checksum of the covered bytes
+    pub expected: u32,
+    /// The value of the checksum field
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} checksum at offset {} does not match (expected: {:#x}, actual: {:#x})",
+               self.algorithm, self.offset, self.expected, self.actual)
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// The tag of a `structure_enum!` does not belong to any of its variants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownTag {
+    /// The name of the enum
+    pub name: &'static str,
+    pub tag: u64,
+}
+
+impl fmt::Display for UnknownTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unknown tag of {} ({:#x})", self.name, self.tag)
+    }
+}
+
+impl Error for UnknownTag {}
+
+/// An enum-typed field (like "B<Status>") has a value that is not a discriminant of its enum
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidDiscriminant {
+    /// The offset of the field from where unpacking started
+    pub offset: u64,
+    /// The index of the field in the unpacked values
+    pub index: usize,
+    /// The name of the enum
+    pub type_name: &'static str,
+    pub value: i128,
+}
+
+impl fmt::Display for InvalidDiscriminant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Value {} of field {} at offset {} is not a discriminant of {}",
+               self.value, self.index, self.offset, self.type_name)
+    }
+}
+
+impl Error for InvalidDiscriminant {}
@@ -0,0 +1,60 @@
+//! User-defined types that can be fields of a format.
+//!
+//! A type that implements `Format` is spliced into a format by its name in braces, like in
+//! `"I {Rgb565} H"`. Its values are packed from references and unpacked to owned values:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! use std::io::{Read, Result, Write};
+//! use structure::byteorder::{ByteOrder, ReadBytesExt, WriteBytesExt};
+//! use structure::format::Format;
+//!
+//! #[derive(Debug, PartialEq)]
+//! struct Rgb565 {
+//!     red: u8,
+//!     green: u8,
+//!     blue: u8,
+//! }
+//!
+//! impl Format for Rgb565 {
+//!     const SIZE: usize = 2;
+//!
+//!     fn encode<E: ByteOrder, W: Write>(&self, wtr: &mut W) -> Result<()> {
+//!         let bits = u16::from(self.red) << 11 | u16::from(self.green) << 5 | u16::from(self.blue);
+//!         wtr.write_u16::<E>(bits)
+//!     }
+//!
+//!     fn decode<E: ByteOrder, R: Read>(rdr: &mut R) -> Result<Rgb565> {
+//!         let bits = rdr.read_u16::<E>()?;
+//!         Ok(Rgb565 { red: (bits >> 11) as u8, green: (bits >> 5 & 0x3f) as u8, blue: (bits & 0x1f) as u8 })
+//!     }
+//! }
+//!
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("<B {Rgb565}");
+//! assert_eq!(s.size(), 3);
+//! let color = Rgb565 { red: 31, green: 0, blue: 1 };
+//! assert_eq!(s.pack(7, &color)?, vec![7, 0x01, 0xf8]);
+//! assert_eq!(s.unpack(&[7, 0x01, 0xf8])?, (7, color));
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+
+use byteorder::ByteOrder;
+use std::io::{Read, Result, Write};
+
+/// A value with a fixed size, that is packed and unpacked by its own code.
+pub trait Format: Sized {
+    /// The number of bytes that `encode` writes and `decode` reads
+    const SIZE: usize;
+
+    /// Write the value, in the byte order of the format it is a field of
+    fn encode<E: ByteOrder, W: Write>(&self, wtr: &mut W) -> Result<()>;
+
+    /// Read a value, in the byte order of the format it is a field of
+    fn decode<E: ByteOrder, R: Read>(rdr: &mut R) -> Result<Self>;
+}
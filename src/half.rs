@@ -0,0 +1,63 @@
+//! Conversions between `f32` and IEEE 754 binary16 (half-precision) bit patterns, used by the
+//! 'e' format character.
+
+/// Convert an `f32` to the bits of the nearest `f16`, rounding ties to even.
+///
+/// Values too large for a half-precision float become infinity, and NaN stays NaN.
+pub fn f32_to_f16(value: f32) -> u16 {
+    let x = value.to_bits();
+    let sign = (x >> 16) & 0x8000;
+    let exp = ((x >> 23) & 0xff) as i32;
+    let man = x & 0x007f_ffff;
+
+    if exp == 0xff {
+        // Infinity or NaN (keep NaN quiet even if the payload is truncated away)
+        let nan_bit = if man != 0 { 0x0200 } else { 0 };
+        return (sign | 0x7c00 | nan_bit | (man >> 13)) as u16;
+    }
+
+    let half_exp = exp - 127 + 15;
+    if half_exp >= 0x1f {
+        // Overflow
+        return (sign | 0x7c00) as u16;
+    }
+
+    if half_exp <= 0 {
+        // Subnormal half (or zero)
+        let shift = (14 - half_exp) as u32;
+        if shift > 24 {
+            return sign as u16;
+        }
+        let man = man | 0x0080_0000;
+        let mut half_man = man >> shift;
+        let round_bit = 1 << (shift - 1);
+        if man & round_bit != 0 && man & (3 * round_bit - 1) != 0 {
+            half_man += 1;
+        }
+        return (sign | half_man) as u16;
+    }
+
+    let mut half = sign | ((half_exp as u32) << 10) | (man >> 13);
+    let round_bit = 0x1000;
+    if man & round_bit != 0 && man & (3 * round_bit - 1) != 0 {
+        // A carry out of the mantissa correctly bumps the exponent (up to infinity)
+        half += 1;
+    }
+    half as u16
+}
+
+/// Convert the bits of an `f16` to an `f32`. Every half-precision value is exactly representable.
+pub fn f16_to_f32(bits: u16) -> f32 {
+    let sign = u32::from(bits & 0x8000) << 16;
+    let exp = u32::from((bits >> 10) & 0x1f);
+    let man = u32::from(bits & 0x03ff);
+    match exp {
+        0 => {
+            // Zero or subnormal: man * 2^-24
+            let value = man as f32 / 16_777_216.0;
+            if sign != 0 { -value } else { value }
+        }
+        0x1f => f32::from_bits(sign | 0x7f80_0000 | (man << 13)),
+        _ => f32::from_bits(sign | ((exp + 127 - 15) << 23) | (man << 13)),
+    }
+}
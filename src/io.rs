@@ -0,0 +1,59 @@
+//! I/O adapters used by the generated code.
+
+use std::io::{Read, Result};
+
+/// A reader that counts the bytes that are read, so the generated code knows the offset of each field.
+pub struct Counter<T> {
+    inner: T,
+    position: u64,
+}
+
+impl<T> Counter<T> {
+    pub fn new(inner: T) -> Counter<T> {
+        Counter { inner, position: 0 }
+    }
+
+    /// The number of bytes read so far
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl<T: Read> Read for Counter<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// A reader that keeps a copy of the bytes that are read, so the generated code can verify checksums
+/// over them.
+pub struct Recorder<T> {
+    inner: T,
+    record: Vec<u8>,
+}
+
+impl<T> Recorder<T> {
+    pub fn new(inner: T) -> Recorder<T> {
+        Recorder { inner, record: vec![] }
+    }
+
+    /// The number of bytes read so far
+    pub fn position(&self) -> u64 {
+        self.record.len() as u64
+    }
+
+    /// The bytes read so far
+    pub fn record(&self) -> &[u8] {
+        &self.record
+    }
+}
+
+impl<T: Read> Read for Recorder<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
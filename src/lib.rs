@@ -73,6 +73,29 @@
 //! '>'         |   big-endian
 //! '!'         |   network (= big-endian)
 //!
+//! The byte order may also be chosen when packing or unpacking, so one format serves peers of both
+//! byte orders: `pack_with`, `pack_into_with`, `unpack_with` and `unpack_from_with` take a
+//! `byteorder::ByteOrder` type instead of using the endianness of the format (bit-level formats
+//! ignore it, and [tagged unions](#tagged-unions) and [named layouts](#named-layouts) have these
+//! methods too):
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! use structure::byteorder::{BigEndian, LittleEndian};
+//!
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("H I");
+//! assert_eq!(s.pack_with::<LittleEndian>(1, 2)?, vec![1, 0, 2, 0, 0, 0]);
+//! assert_eq!(s.pack_with::<BigEndian>(1, 2)?, s.pack(1, 2)?);
+//! assert_eq!(s.unpack_with::<LittleEndian, _>(&[1, 0, 2, 0, 0, 0])?, (1, 2));
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
 //! ## Types
 //!
 //! Character   |   Type
@@ -86,18 +109,56 @@
 //! 'I'         |   `u32`
 //! 'q'         |   `i64`
 //! 'Q'         |   `u64`
+//! 'o'         |   `i128`
+//! 'O'         |   `u128`
+//! 'u<N>'      |   N-bit unsigned integer (`u8`/`u16`/`u32`/`u64`/`u128`)
+//! 'i<N>'      |   N-bit signed integer (`i8`/`i16`/`i32`/`i64`/`i128`)
+//! 'n'         |   `isize`
+//! 'N'         |   `usize`
+//! 'v'         |   `u64` (unsigned LEB128)
+//! 'V'         |   `i64` (signed LEB128)
+//! 'w'         |   `u64` (VLQ)
+//! 'k'         |   `i32` (zigzag LEB128)
+//! 'K'         |   `i64` (zigzag LEB128)
 //! 'f'         |   `f32`
 //! 'd'         |   `f64`
+//! 'e'         |   `f32` (half-precision)
 //! 's'         |   `&[u8]`
 //! 'S'         |   `&[u8]`
+//! 's<T>'      |   `&[u8]` (length-prefixed)
+//! 'p'         |   `&[u8]` (Pascal string)
+//! 'z'         |   `&[u8]` (NUL-terminated string)
+//! '*'         |   `&[u8]` (the rest of the bytes)
+//! '~(...)'    |   `&[T]` (sentinel-terminated array)
+//! '{T}'       |   `&T` (user-defined type)
+//! '{T}<P>'    |   `&T` (length-prefixed record)
+//! 'c'         |   `char` (ASCII, 1 byte)
 //! 'P'         |   `*const c_void`
+//! '#<...>'    |   checksum
 //! 'x'         |   padding (1 byte)
+//! 'x<N>'      |   padding to a multiple of N bytes
 //!
+//! * Whitespace between format characters is ignored, so "2I B" is the same as "2IB".
 //! * Any format character may be preceded by an integral repeat count. For example, the format string '4h'
-//! means exactly the same as 'hhhh'.
+//!   means exactly the same as 'hhhh'.
+//! * Like 'P', 'n' and 'N' can be used only if the endianness is native ('='), and their size is
+//!   the size of a pointer.
+//! * 'c' is a single ASCII character. Packing a non-ASCII `char` fails with `ErrorKind::InvalidInput`,
+//!   and unpacking a byte above 0x7f fails with `ErrorKind::InvalidData`.
+//! * '{T}' is a field of a type `T` that implements [`Format`](format/trait.Format.html), which
+//!   packs and unpacks it by its own code (like a [named layout](#named-layouts)). It is packed from
+//!   `&T` and unpacked to `T`, and its size is `T::SIZE`.
+//! * '{T}<P>' is a user-defined type prefixed by its length in bytes, of the type P (like in 's<P>').
+//!   When unpacking, `T` may not read past that length (which fails with `ErrorKind::InvalidData`),
+//!   and the bytes it doesn't read are skipped, so records may grow new fields that older readers
+//!   ignore.
+//! * 'x<N>' is as many zero bytes as needed for the next field to start at a multiple of N bytes,
+//!   from the start of the format. When the offset depends on the values (after a variable-size
+//!   field or a user-defined type), the format has a variable size, which is at least as if no
+//!   padding were needed. Unpacking skips the same number of bytes.
 //! * 'P' may be follow by a `<type>`, so `"P<u32>"` means a pointer to u32 (`*const u32`).
 //! * When 's' is packed, its value can be smaller than the size specified in the format,
-//! and the rest will be filled with zeros. For instance:
+//!   and the rest will be filled with zeros. For instance:
 //!
 //! ```rust
 //! # #[macro_use]
@@ -112,23 +173,395 @@
 //! ```
 //!
 //! * Unlike 's', 'S' is a fixed-size buffer, so the size of its value must be exactly the size
-//! specified in the format.
+//!   specified in the format.
+//! * 's' may be followed by the type of a length prefix, which is one of 'B', 'H', 'I', 'Q' or 'O'.
+//!   For example, "s<H>" is a `u16` length (in the format's byte order) followed by that many bytes.
+//!   The prefix is written on pack from the length of the value, and unpacking returns only the data:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("<s<H>B");
+//! assert_eq!(s.pack(b"abc", 4)?, vec![3, 0, b'a', b'b', b'c', 4]);
+//! assert_eq!(s.unpack(&[3, 0, b'a', b'b', b'c', 4])?, (b"abc".to_vec(), 4));
+//! assert_eq!(s.size(), 3); // The minimum size
+//! # Ok(())
+//! # }
+//! # fn main() {
+//!     # foo().unwrap();
+//! # }
+//! ```
+//!
+//! * 'p' is a Pascal string: a field of the size specified in the format, whose first byte is the
+//!   length of the data that follows. On pack, the data is truncated to fit the field (and 255 bytes),
+//!   and the rest of the field is filled with zeros. On unpack, only the data is returned:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("5p");
+//! assert_eq!(s.pack(b"ab")?, vec![2, b'a', b'b', 0, 0]);
+//! assert_eq!(s.unpack(&[2, b'a', b'b', 0, 0])?, (b"ab".to_vec(), ));
+//! # Ok(())
+//! # }
+//! # fn main() {
+//!     # foo().unwrap();
+//! # }
+//! ```
+//!
+//! * 'z' without a count is a NUL-terminated string of any length. On pack, the NUL is appended,
+//!   and on unpack, the string is read up to the NUL, which is not returned. With a count, like in
+//!   "8z", it is a NUL-padded field of that size, and its value ends at the first NUL (the value may
+//!   fill the whole field). Packing a value that contains a NUL fails with `ErrorKind::InvalidInput`.
+//! * '*' is all the bytes that are left, so it can only be the last format character. `pack`
+//!   writes the whole slice, `unpack` takes the rest of the buffer, and `unpack_from` reads until
+//!   the end of the reader. For example, "!HH*" is two `u16`s followed by a payload of any length.
+//! * An integer field ('b', 'B', 'h', 'H', 'i', 'I', 'q' or 'Q') may be followed by the name of an
+//!   enum in angle brackets, like in "B<Status>", to pack it from and unpack it to the enum. The enum
+//!   implements `Into<u8>` and `TryFrom<u8>` (for the integer type of the field), usually for its
+//!   `#[repr]` discriminants. A value that is not converted to the enum fails to unpack with
+//!   `ErrorKind::InvalidData` and an [`InvalidDiscriminant`](error/struct.InvalidDiscriminant.html)
+//!   error, which has the offset of the field and its index in the unpacked values. In the variants
+//!   of `structure_enum!`, in named layouts and in the entries of '~' arrays, which are packed by
+//!   reference, the enum also implements `Clone`:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! use std::convert::TryFrom;
+//!
+//! #[derive(Debug, Clone, Copy, PartialEq)]
+//! #[repr(u8)]
+//! enum Status {
+//!     Ready = 1,
+//!     Done = 2,
+//! }
+//!
+//! impl TryFrom<u8> for Status {
+//!     type Error = u8;
+//!
+//!     fn try_from(value: u8) -> Result<Status, u8> {
+//!         match value {
+//!             1 => Ok(Status::Ready),
+//!             2 => Ok(Status::Done),
+//!             _ => Err(value),
+//!         }
+//!     }
+//! }
+//!
+//! impl From<Status> for u8 {
+//!     fn from(status: Status) -> u8 {
+//!         status as u8
+//!     }
+//! }
+//!
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("B<Status> H");
+//! assert_eq!(s.pack(Status::Done, 7)?, vec![2, 0, 7]);
+//! assert_eq!(s.unpack(&[1, 0, 7])?, (Status::Ready, 7));
+//! assert!(s.unpack(&[3, 0, 7]).is_err());
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
+//! * An unsigned integer of a fixed size may be split into bitfields by their widths and names in
+//!   braces, like in "H{4:version 4:ihl 8:tos}". Each named bitfield is an argument and a result
+//!   of the narrowest type that holds it (`bool` for a single bit, then `u8`, `u16`, `u32`, `u64`
+//!   or `u128`), and bitfields without a name (like the "3" in "B{1:flag 3 4:kind}") are reserved
+//!   bits that are packed as zeros. The widths must add up to the width of the integer. By default
+//!   the first bitfield is the most significant bits, and "lsb" before the bitfields (like in
+//!   "B{lsb 1:flag 7:kind}") makes it the least significant ones. Packing a value that does not
+//!   fit in its bitfield fails with `ErrorKind::InvalidInput`. A single-bit bitfield can also be
+//!   the flag of a conditional field, like in "B{1:has_length 7:kind} [has_length]H":
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! // The first bytes of an IPv4 header
+//! let s = structure!("!B{4:version 4:ihl} B H");
+//! assert_eq!(s.pack(4, 5, 0, 20)?, vec![0x45, 0, 0, 20]);
+//! assert_eq!(s.unpack(&[0x45, 0, 0, 20])?, (4, 5, 0, 20));
+//! assert!(s.pack(16, 5, 0, 20).is_err());
+//!
+//! let s = structure!("B{1:has_length 7:kind} [has_length]H");
+//! assert_eq!(s.pack(true, 3, Some(20))?, vec![0x83, 0, 20]);
+//! assert_eq!(s.unpack(&[0x03])?, (false, 3, None));
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
+//! * '~' is an array of entries that ends with a sentinel entry, followed by the fields of an entry
+//!   in parentheses, like in "~(2I)". An entry has a fixed size, and is a tuple if it has more than
+//!   one field (so "~(2I)" is a `&[(u32, u32)]` on pack and a `Vec<(u32, u32)>` on unpack). The
+//!   sentinel is an all-zero entry by default. It can be given after a '=', as an integer if an
+//!   entry is a single integer (like in "~(H)=0xFFFF"), or as a byte string literal. `pack`
+//!   writes the sentinel after the entries, and `unpack` reads entries until the sentinel, which is
+//!   not part of the results. A maximum number of entries may follow, like in "~(2I)<=64": more
+//!   entries fail to pack with `ErrorKind::InvalidInput`, and to unpack with `ErrorKind::InvalidData`.
+//! * When a format has fields of variable length, `size()` returns its minimum size, and `unpack`
+//!   fails unless the buffer is consumed exactly. `size_hint()` returns the minimum size and the
+//!   maximum size (or `None` if the size is unbounded), like `Iterator::size_hint`.
+//! * An integer format character may be followed by '=' and a literal, like in "I=0xCAFEBABE", and
+//!   a quoted byte string like '\x7fELF' may appear between format characters. These literal fields
+//!   are written by `pack` and checked by `unpack`, and are not part of the arguments or the results.
+//!   When a literal field does not match, unpacking fails with `ErrorKind::InvalidData` and a
+//!   [`LiteralMismatch`](error/struct.LiteralMismatch.html) error:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("<'\x7fELF' B=2 H");
+//! assert_eq!(s.pack(3)?, vec![0x7f, b'E', b'L', b'F', 2, 3, 0]);
+//! assert_eq!(s.unpack(&[0x7f, b'E', b'L', b'F', 2, 3, 0])?, (3, ));
+//! assert!(s.unpack(&[0x7f, b'E', b'L', b'F', 1, 3, 0]).is_err());
+//! # Ok(())
+//! # }
+//! # fn main() {
+//!     # foo().unwrap();
+//! # }
+//! ```
+//!
+//! * '#' is a checksum field, followed by the algorithm and optionally the bytes it covers, like in
+//!   "#<crc32 4>". The algorithm is one of `crc32` (CRC-32, a `u32`), `crc16` (CRC-16/CCITT-FALSE, a
+//!   `u16`), `internet` (the Internet checksum of RFC 1071, a `u16`) or `xor8` (a `u8`). The covered
+//!   bytes are given by offsets from the start of the record: "a" covers from offset a up to the
+//!   checksum field (the default is 0), "a..b" covers from offset a to offset b, and "a.." covers
+//!   from offset a to the end of the record. Checksums are computed by `pack` and verified by
+//!   `unpack`, and are not part of the arguments or the results. While a checksum is computed, its
+//!   own field (and the checksum fields after it) are zeros. When a checksum does not match,
+//!   unpacking fails with `ErrorKind::InvalidData` and a
+//!   [`ChecksumMismatch`](error/struct.ChecksumMismatch.html) error:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! // An IPv4 header, whose checksum covers the whole header
+//! let s = structure!("!BBHHHBB#<internet 0..>4S4S");
+//! let packed = s.pack(0x45, 0, 0x73, 0, 0x4000, 0x40, 0x11, &[192, 168, 0, 1], &[192, 168, 0, 199])?;
+//! assert_eq!(packed[10..12], [0xb8, 0x61]);
+//! assert_eq!(s.unpack(&packed)?.0, 0x45);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//!     # foo().unwrap();
+//! # }
+//! ```
+//!
+//! * A field may be named by a ':' and a name after it, and a later field may take its count from a
+//!   named field by a name in parentheses before it, like in "H:count (count)I", which is a `u16`
+//!   followed by that many `u32`s. The count field is an unsigned integer ('B', 'H', 'I', 'Q',
+//!   'u<N>', 'v' or 'w'), and the fields that use it are arrays (`&[T]` on pack and `Vec<T>` on
+//!   unpack) or, for 's' and 'S', buffers whose byte length it holds. The count field is not part of
+//!   the arguments or the results, since `pack` writes the length of its fields (which must be
+//!   equal if they share it). A limit may follow the name, like in "(count<=100)": longer fields
+//!   fail to pack with `ErrorKind::InvalidInput`, and greater counts fail to unpack with
+//!   `ErrorKind::InvalidData`, before anything is allocated for them.
+//! * A field may be conditional on a named field by a test in brackets before it: "[flags]" tests
+//!   that the field is nonzero (or `true`, for a `bool`), and "[flags&0x80]" tests that any of the
+//!   given bits is set. The tested field is a single unsigned integer or `bool` that comes earlier.
+//!   A conditional field is an `Option` in the arguments and the results, like `Option<u16>` for
+//!   "[flags&0x80]H". `pack` fails with `ErrorKind::InvalidInput` if a conditional field is given
+//!   when its test fails, or is not given when its test holds:
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! // The port is present only if the low bit of the flags is set
+//! let s = structure!("!B:flags [flags&1]H");
+//! assert_eq!(s.pack(1, Some(8080))?, vec![1, 0x1f, 0x90]);
+//! assert_eq!(s.unpack(&[0])?, (0, None));
+//! assert!(s.pack(0, Some(8080)).is_err());
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
 //! * By default, 's' and 'S' are buffers of one byte. To create a fixed-sized buffer with ten bytes,
-//! the format would be "10S".
+//!   the format would be "10S".
+//! * 'u<N>' and 'i<N>' are integers of N bits, where N is a multiple of 8 between 8 and 128, so
+//!   "u<24>" is a 3-byte unsigned integer. They are represented by the smallest Rust integer that
+//!   holds them, signed integers are sign-extended on unpack, and packing a value that does not fit
+//!   fails with `ErrorKind::InvalidInput`.
+//! * 'v', 'V' and 'w' are variable-length integers made of 7-bit groups, in which the high bit of
+//!   each byte tells whether another byte follows. 'v' and 'V' are LEB128 (least significant group
+//!   first, as in WebAssembly, DWARF and protobuf), and 'w' is VLQ (most significant group first, as
+//!   in MIDI). They take 1 to 10 bytes, and unpacking a longer varint or a value that does not fit
+//!   in 64 bits fails with `ErrorKind::InvalidData`.
+//! * 'k' and 'K' are signed integers that are zigzag-encoded (0, -1, 1, -2... become 0, 1, 2, 3...)
+//!   and then stored like 'v', as in protobuf's `sint32` and `sint64`.
+//! * 'e' is an IEEE 754 half-precision float (2 bytes). It is packed from an `f32`, rounding to
+//!   the nearest representable value (ties to even), and unpacked to an `f32`.
 //! * On unpack, 'x' skips a byte. On pack, 'x' always writes a null byte. To skip multiple bytes,
-//! prepend the length like in "10x".
+//!   prepend the length like in "10x".
+//!
+//! ## Bit-Level Formats
+//!
+//! A format that starts with '%' is made of fields that take any number of bits, and are not aligned
+//! to bytes. Its items are separated by whitespace: a number (or 'u' and a number) is an unsigned
+//! field of that many bits, 'i' and a number is a signed field, and 'x' and a number is padding bits
+//! (zeros). A field takes 1 to 64 bits, and is an argument and a result of the narrowest type that
+//! holds it (`bool` for a single unsigned bit). The fields are MSB-first by default: bits fill each
+//! byte from its most significant bit, and each field is written from its most significant bit.
+//! '%<' makes both LSB-first (and '%>' is MSB-first). The last byte is padded with zero bits, so
+//! `size()` is the size in bytes, while `size_in_bits()` is the size without the padding. Packing a
+//! value that does not fit in its field fails with `ErrorKind::InvalidInput`.
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate structure;
+//! # fn foo() -> std::io::Result<()> {
+//! // A 12-bit field followed by a 5-bit one
+//! let s = structure!("%12 5");
+//! assert_eq!(s.size_in_bits(), 17);
+//! assert_eq!(s.size(), 3);
+//! assert_eq!(s.pack(0xabc, 0b10101)?, vec![0xab, 0xca, 0x80]);
+//! assert_eq!(s.unpack(&[0xab, 0xca, 0x80])?, (0xabc, 0b10101));
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
+//! # Tagged Unions
+//!
+//! `structure_enum!` declares an enum whose variants are told apart by a tag, like a message type
+//! that is followed by one of several message bodies. The tag has its own format (a single unsigned
+//! integer of at most 64 bits), and each variant has a tag value and the format of its fields. A
+//! variant's format has the endianness of the tag's format, unless it starts with its own (which
+//! the `_with` methods keep too, while they use the chosen byte order for the tag and the others).
+//! The variants hold the values that `unpack` returns for their formats (a variant without fields is
+//! a unit variant), and the enum gets `tag`, `pack`, `pack_into`, `unpack` and `unpack_from` methods.
+//! An unknown tag fails to unpack with `ErrorKind::InvalidData` and an
+//! [`UnknownTag`](error/struct.UnknownTag.html) error. Since there can be a single `structure_enum!`
+//! per module, it may declare several enums (and [named layouts](#named-layouts)):
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate structure;
+//!
+//! structure_enum! {
+//!     #[derive(Debug, PartialEq)]
+//!     enum Message: "!B" {
+//!         Ping = 1 => "I",
+//!         Data = 2 => "H:length (length)s",
+//!         Quit = 3 => "",
+//!     }
+//! }
+//!
+//! # fn foo() -> std::io::Result<()> {
+//! let packed = Message::Data(b"hello".to_vec()).pack()?;
+//! assert_eq!(packed, b"\x02\x00\x05hello");
+//! assert_eq!(Message::unpack(&packed)?, Message::Data(b"hello".to_vec()));
+//! assert_eq!(Message::unpack(&[3])?, Message::Quit);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
+//! # Named Layouts
+//!
+//! `structure_enum!` also declares named layouts, like `struct Header: "2H I";`. A named layout is a
+//! tuple struct of the values that `unpack` returns for its format, with `pack`, `pack_into`, `unpack`
+//! and `unpack_from` methods. Its format must have a fixed size, and it implements
+//! [`Format`](format/trait.Format.html), so other formats may nest it like in `"{Header} 2I"`, where
+//! it is packed from a `&Header` and unpacked to a `Header`. A nested layout keeps the byte order of
+//! its own format if it has one, and otherwise takes the byte order of the format it is nested in.
+//!
+//! Named layouts are only declared by `structure_enum!`, which can be used once per module, so the
+//! layouts (and enums) of a module are all declared in one block. Layouts may also be declared in
+//! another module, and imported with `use`:
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate structure;
+//!
+//! mod layouts {
+//!     structure_enum! {
+//!         #[derive(Debug, PartialEq)]
+//!         pub struct Header: "<H B";
+//!     }
+//! }
+//!
+//! use layouts::Header;
+//!
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!(">{Header} 2I");
+//! assert_eq!(s.size(), 11);
+//! let packed = s.pack(&Header(1, 2), 3, 4)?;
+//! assert_eq!(packed, b"\x01\x00\x02\x00\x00\x00\x03\x00\x00\x00\x04");
+//! assert_eq!(s.unpack(&packed)?, (Header(1, 2), 3, 4));
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
+//! # Offsets
+//!
+//! An unsigned integer followed by '@' and a user-defined type in braces, like "I@{Header}", is the
+//! offset of a value of that type (usually a [named layout](#named-layouts)) elsewhere in the data.
+//! `pack`, `unpack` and `unpack_from` treat it as a plain integer. In addition, such a format has
+//! an `unpack_from_seek` method for readers that implement `Seek`. It unpacks the record, seeks to
+//! each offset (added to the `base` argument, like the position of a file header for relative
+//! offsets) and decodes the value there in place of the offset, and then seeks back to the end of
+//! the record:
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate structure;
+//!
+//! structure_enum! {
+//!     #[derive(Debug, PartialEq)]
+//!     struct Section: "2H";
+//! }
+//!
+//! # fn foo() -> std::io::Result<()> {
+//! let s = structure!("B I@{Section}");
+//! let data = b"\x07\x00\x00\x00\x08\xff\xff\xff\x00\x01\x00\x02";
+//! assert_eq!(s.unpack(&data[..5])?, (7, 8));
+//! let mut rdr = std::io::Cursor::new(&data[..]);
+//! assert_eq!(s.unpack_from_seek(&mut rdr, 0)?, (7, Section(1, 2)));
+//! assert_eq!(rdr.position(), 5);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     foo().unwrap();
+//! # }
+//! ```
+//!
+//! Like the other `_with` methods, `unpack_from_seek_with` takes the byte order of the record and of
+//! the values at the offsets.
 //!
 //! # Differences from Python struct library
 //!
 //! While the format strings look very similar to Python's `struct` library, there are a few differences:
 //!
 //! * Numbers' byte order is big-endian by default (e.g. u32, f64...).
-//! * There is no alignment support.
+//! * There is no automatic alignment, but 'x<N>' aligns the next field explicitly.
 //! * In addition to 's' (buffer) format character, that when packed, its value can be smaller than
-//! the size specified in the format, there is the 'S' format character, that the size of its value must
-//! be exactly the size specified in the format.
+//!   the size specified in the format, there is the 'S' format character, that the size of its value must
+//!   be exactly the size specified in the format.
 //! * The type of a pointer is `c_void` by default, but can be changed.
 //! * 32 bit integer format character is only 'I'/'i' (and not 'L'/'l').
+//! * 128 bit integers ('o'/'O') are supported.
 //! * structure!() macro takes a literal string as an argument.
 //! * It's called `structure` because `struct` is a reserved keyword in Rust.
 
@@ -138,6 +571,19 @@ extern crate proc_macro_hack;
 #[doc(hidden)]
 pub extern crate byteorder;
 
+#[doc(hidden)]
+pub mod bits;
+#[doc(hidden)]
+pub mod checksum;
+pub mod error;
+pub mod format;
+#[doc(hidden)]
+pub mod half;
+#[doc(hidden)]
+pub mod io;
+#[doc(hidden)]
+pub mod varint;
+
 
 // Allow the "unused" #[macro_use] because there is a different un-ignorable
 // warning otherwise:
@@ -152,3 +598,7 @@ pub use structure_macro_impl::*;
 proc_macro_expr_decl! {
     structure! => structure_impl
 }
+
+proc_macro_item_decl! {
+    structure_enum! => structure_enum_impl
+}
@@ -0,0 +1,147 @@
+//! Readers and writers of variable-length integers, used by the 'v', 'V', 'w', 'k' and 'K' format
+//! characters.
+//!
+//! Varints are made of 7-bit groups, where the high bit of each byte tells whether another byte
+//! follows, so they have no byte order.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use byteorder::{ReadBytesExt, WriteBytesExt};
+
+/// The maximum number of bytes of an encoded 64-bit varint
+pub const MAX_VARINT_LENGTH: usize = 10;
+
+fn overflow_error() -> Error {
+    Error::new(ErrorKind::InvalidData, "Varint is too large for a 64-bit integer")
+}
+
+/// Read an unsigned LEB128 integer (least significant group first).
+pub fn read_uleb128<R: Read>(rdr: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_LENGTH {
+        let byte = rdr.read_u8()?;
+        if i == MAX_VARINT_LENGTH - 1 && byte > 1 {
+            // Only the highest bit of the value is left for the last byte
+            return Err(overflow_error());
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(overflow_error())
+}
+
+/// Write an unsigned LEB128 integer (least significant group first).
+pub fn write_uleb128<W: Write>(wtr: &mut W, mut value: u64) -> Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return wtr.write_u8(byte);
+        }
+        wtr.write_u8(byte | 0x80)?;
+    }
+}
+
+/// Read a signed LEB128 integer (two's complement, least significant group first).
+pub fn read_sleb128<R: Read>(rdr: &mut R) -> Result<i64> {
+    let mut value = 0i64;
+    for i in 0..MAX_VARINT_LENGTH {
+        let byte = rdr.read_u8()?;
+        let shift = 7 * i;
+        if i == MAX_VARINT_LENGTH - 1 {
+            // The last byte holds the sign bit of the value, and the rest must extend it
+            if byte != 0x00 && byte != 0x7f {
+                return Err(overflow_error());
+            }
+            return Ok(value | (i64::from(byte) << shift));
+        }
+        value |= i64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            if byte & 0x40 != 0 {
+                // Sign-extend
+                value |= -1i64 << (shift + 7);
+            }
+            return Ok(value);
+        }
+    }
+    Err(overflow_error())
+}
+
+/// Write a signed LEB128 integer (two's complement, least significant group first).
+pub fn write_sleb128<W: Write>(wtr: &mut W, mut value: i64) -> Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let sign_bit = byte & 0x40 != 0;
+        if (value == 0 && !sign_bit) || (value == -1 && sign_bit) {
+            return wtr.write_u8(byte);
+        }
+        wtr.write_u8(byte | 0x80)?;
+    }
+}
+
+/// Read a VLQ integer (most significant group first, as in MIDI files).
+pub fn read_vlq<R: Read>(rdr: &mut R) -> Result<u64> {
+    let mut value = 0u64;
+    for _ in 0..MAX_VARINT_LENGTH {
+        let byte = rdr.read_u8()?;
+        if value >> 57 != 0 {
+            return Err(overflow_error());
+        }
+        value = (value << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(overflow_error())
+}
+
+/// Write a VLQ integer (most significant group first, as in MIDI files).
+pub fn write_vlq<W: Write>(wtr: &mut W, value: u64) -> Result<()> {
+    let mut buf = [0u8; MAX_VARINT_LENGTH];
+    let mut start = MAX_VARINT_LENGTH;
+    let mut rest = value;
+    loop {
+        start -= 1;
+        buf[start] = (rest & 0x7f) as u8;
+        if start != MAX_VARINT_LENGTH - 1 {
+            buf[start] |= 0x80;
+        }
+        rest >>= 7;
+        if rest == 0 {
+            break;
+        }
+    }
+    wtr.write_all(&buf[start..])
+}
+
+/// Read a zigzag-encoded 32-bit signed integer, stored as an unsigned LEB128 integer (like
+/// protobuf's `sint32`).
+pub fn read_zigzag32<R: Read>(rdr: &mut R) -> Result<i32> {
+    let value = read_uleb128(rdr)?;
+    if value > u64::from(u32::MAX) {
+        return Err(Error::new(ErrorKind::InvalidData, "Varint is too large for a 32-bit integer"));
+    }
+    let value = value as u32;
+    Ok((value >> 1) as i32 ^ -((value & 1) as i32))
+}
+
+/// Write a zigzag-encoded 32-bit signed integer, stored as an unsigned LEB128 integer (like
+/// protobuf's `sint32`).
+pub fn write_zigzag32<W: Write>(wtr: &mut W, value: i32) -> Result<()> {
+    write_uleb128(wtr, u64::from(((value << 1) ^ (value >> 31)) as u32))
+}
+
+/// Read a zigzag-encoded 64-bit signed integer, stored as an unsigned LEB128 integer (like
+/// protobuf's `sint64`).
+pub fn read_zigzag64<R: Read>(rdr: &mut R) -> Result<i64> {
+    let value = read_uleb128(rdr)?;
+    Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+}
+
+/// Write a zigzag-encoded 64-bit signed integer, stored as an unsigned LEB128 integer (like
+/// protobuf's `sint64`).
+pub fn write_zigzag64<W: Write>(wtr: &mut W, value: i64) -> Result<()> {
+    write_uleb128(wtr, ((value << 1) ^ (value >> 63)) as u64)
+}
@@ -1,123 +1,879 @@
-#![recursion_limit = "128"]
+#![recursion_limit = "256"]
 
 #[macro_use]
 extern crate proc_macro_hack;
 #[macro_use]
 extern crate quote;
 
+use std::iter::Peekable;
 use std::mem;
-use std::os::raw::c_void;
 use std::string::String;
 use quote::{Tokens, Ident};
 
+/// The maximum number of bytes of an encoded 64-bit varint (same as `structure::varint`)
+const MAX_VARINT_LENGTH: usize = 10;
+/// The maximum number of bytes of an encoded 32-bit varint
+const MAX_VARINT32_LENGTH: usize = 5;
+
 proc_macro_expr_impl! {
     pub fn structure_impl(input: &str) -> String {
-        let format = trim_quotes(input);
-        let struct_name = Ident::from(format_to_struct_name(format));
-        let (values, endianness) = format_to_values(&format);
-        let (args, fn_decl_args, args_types) = build_args_list(&values);
-        let endianness = match endianness {
-            Endianness::Native => {
-                if cfg!(target_endian = "little") {
-                    quote!(LittleEndian)
-                } else {
-                    quote!(BigEndian)
+        build_structure(trim_quotes(input)).into_string()
+    }
+}
+
+proc_macro_item_impl! {
+    pub fn structure_enum_impl(input: &str) -> String {
+        let mut output = Tokens::new();
+        for declaration in parse_declarations(input) {
+            output.append(match declaration {
+                Declaration::Enum(declaration) => build_enum(&declaration),
+                Declaration::Layout(declaration) => build_layout(&declaration),
+            });
+        }
+        output.into_string()
+    }
+}
+
+/// Build an expression that evaluates to a structure of the given format
+fn build_structure(format: &str) -> Tokens {
+    if let Some(bit_format) = format.strip_prefix('%') {
+        return build_bit_structure(format, bit_format);
+    }
+    let struct_name = Ident::from(format_to_struct_name(format));
+    let (values, endianness) = format_to_values(format);
+    let (args, fn_decl_args, args_types) = build_args_list(&values);
+    let endianness = endianness_tokens(&endianness);
+    let size = build_size(calc_size(&values), &values, false);
+    let max_size = calc_max_size(&values).map(|max_size| build_size(max_size, &values, true));
+    let pack_fn = build_pack_fn(&args, &fn_decl_args, &size, &endianness);
+    let pack_into_fn = build_pack_into_fn(&values, &fn_decl_args, &quote!(__E), &size);
+    let unpack_fn = build_unpack_fn(&args_types, &size, is_variable_size(&values), &endianness);
+    let unpack_from_fn = build_unpack_from_fn(&values, &args, &args_types, &quote!(__E));
+    let byte_order_fns = build_byte_order_fns(&args, &fn_decl_args, &args_types, &endianness);
+    let unpack_from_seek_fn = build_unpack_from_seek_fn(&values, &args, &endianness);
+    let size_fn = build_size_fn(&size, max_size);
+    quote! {{
+        #[derive(Debug)]
+        #[allow(non_camel_case_types)]
+        struct #struct_name;
+        #[allow(unused_imports)]
+        use std::io::{Result, Write, Read, Seek, SeekFrom, Error, ErrorKind, Cursor};
+        #[allow(unused_imports)]
+        use std::convert::TryFrom;
+        #[allow(unused_imports)]
+        use structure::byteorder::{ByteOrder, WriteBytesExt, ReadBytesExt, BigEndian, LittleEndian};
+
+        #[allow(unused)] static TRUE_BUF: &[u8] = &[1];
+        #[allow(unused)] static FALSE_BUF: &[u8] = &[0];
+
+        #[allow(clippy::too_many_arguments)]
+        impl #struct_name {
+            #pack_fn
+            #pack_into_fn
+            #unpack_fn
+            #unpack_from_fn
+            #byte_order_fns
+            #unpack_from_seek_fn
+            #size_fn
+        }
+
+        #struct_name // Create structure instance
+    }}
+}
+
+/// A field of a bit-level format
+struct BitItem {
+    width: usize,
+    signed: bool,
+    /// Whether the bits are padding, which is not an argument
+    padding: bool,
+}
+
+impl BitItem {
+    /// Return the narrowest type that holds the item
+    fn type_name(&self) -> &'static str {
+        match (self.signed, self.width) {
+            (false, 1) => "bool",
+            (false, 2..=8) => "u8",
+            (false, 9..=16) => "u16",
+            (false, 17..=32) => "u32",
+            (false, _) => "u64",
+            (true, 2..=8) => "i8",
+            (true, 9..=16) => "i16",
+            (true, 17..=32) => "i32",
+            (true, _) => "i64",
+        }
+    }
+}
+
+/// Parse a bit-level format (after its '%'), like "12 u5 i3 x4", and return its items and whether
+/// it is LSB-first
+fn parse_bit_items(format: &str) -> (Vec<BitItem>, bool) {
+    let (format, lsb_first) = match format.chars().next() {
+        Some('<') => (&format[1..], true),
+        Some('>') => (&format[1..], false),
+        _ => (format, false),
+    };
+    let items = format.split_whitespace().map(|word| {
+        let (width, signed, padding) = match word.chars().next() {
+            Some('i') => (&word[1..], true, false),
+            Some('u') => (&word[1..], false, false),
+            Some('x') => (&word[1..], false, true),
+            _ => (word, false, false),
+        };
+        let width: usize = width.parse().unwrap_or_else(|_| panic!("Invalid bit-level field: '{}'", word));
+        if width == 0 || width > 64 || signed && width == 1 {
+            panic!("Bit-level field '{}' must have 1 to 64 bits (and at least 2 if it is signed)", word);
+        }
+        BitItem { width, signed, padding }
+    }).collect();
+    (items, lsb_first)
+}
+
+/// Return the type of each argument of a bit-level format
+fn bit_arg_types(items: &[BitItem]) -> Vec<(String, String)> {
+    items.iter().filter(|item| !item.padding)
+        .map(|item| (item.type_name().to_owned(), item.type_name().to_owned()))
+        .collect()
+}
+
+/// Build an expression that evaluates to a structure of a bit-level format
+fn build_bit_structure(format: &str, bit_format: &str) -> Tokens {
+    let struct_name = Ident::from(format_to_struct_name(format));
+    let (items, lsb_first) = parse_bit_items(bit_format);
+    let size_in_bits: usize = items.iter().map(|item| item.width).sum();
+    let size = size_in_bits.div_ceil(8);
+
+    let mut args = vec![];
+    let mut fn_decl_args = vec![];
+    let mut writings = Tokens::new();
+    let mut readings = Tokens::new();
+    for item in &items {
+        let width = item.width as u32;
+        if item.padding {
+            writings.append(quote! { wtr.write_bits(0, #width)?; });
+            readings.append(quote! { rdr.read_bits(#width)?; });
+            continue;
+        }
+        let arg = Ident::from(format!("_{}", args.len() + 1));
+        let item_type = Ident::from(item.type_name());
+        fn_decl_args.push(quote!(#arg: #item_type));
+        if item.width > 1 && item.width < type_size(item.type_name()) * 8 {
+            let (min, max) = if item.signed {
+                (-(1i128 << (item.width - 1)), (1i128 << (item.width - 1)) - 1)
+            } else {
+                (0, (1i128 << item.width) - 1)
+            };
+            let min = Ident::from(format!("{}{}", min, item.type_name()));
+            let max = Ident::from(format!("{}{}", max, item.type_name()));
+            writings.append(quote! {
+                if !(#min..=#max).contains(&#arg) {
+                    let msg = format!("Value does not fit in {} bits (value: {})", #width, #arg);
+                    return Err(Error::new(ErrorKind::InvalidInput, msg));
                 }
-            }
-            Endianness::LittleEndian => quote!(LittleEndian),
-            Endianness::BigEndian => quote!(BigEndian),
+            });
+        }
+        writings.append(quote! { wtr.write_bits(#arg as u64, #width)?; });
+        let reading = if !item.signed && item.width == 1 {
+            quote!(rdr.read_bits(1)? != 0)
+        } else if item.signed && item.width < 64 {
+            // Extend the sign bit
+            let shift = 64 - width;
+            quote!(((rdr.read_bits(#width)? << #shift) as i64 >> #shift) as #item_type)
+        } else {
+            quote!(rdr.read_bits(#width)? as #item_type)
         };
-        let size = calc_size(&values);
-        let pack_fn = build_pack_fn(&args, &fn_decl_args, size);
-        let pack_into_fn = build_pack_into_fn(&values, &fn_decl_args, &endianness);
-        let unpack_fn = build_unpack_fn(&args_types, size);
-        let unpack_from_fn = build_unpack_from_fn(&values, &args, &args_types, &endianness);
-        let size_fn = build_size_fn(size);
-        let output = quote! {{
-            #[derive(Debug)]
-            #[allow(non_camel_case_types)]
-            struct #struct_name;
-            #[allow(unused_imports)]
-            use std::io::{Result, Write, Read, Error, ErrorKind, Cursor};
-            #[allow(unused_imports)]
-            use std::os::raw::c_void;
-            #[allow(unused_imports)]
-            use structure::byteorder::{WriteBytesExt, ReadBytesExt, BigEndian, LittleEndian};
+        readings.append(quote! { let #arg = #reading; });
+        args.push(arg);
+    }
+    let args = quote!(#(#args,)*);
+    let fn_decl_args = quote!(#(#fn_decl_args,)*);
+    let args_types = bit_arg_types(&items).into_iter().map(|(_, result_type)| Ident::from(result_type));
+    let args_types = quote!(#(#args_types,)*);
+    let size = quote!(#size);
+    // Bit-level formats have no byte order, but they have the same methods as other formats
+    let byte_order = quote!(structure::byteorder::BigEndian);
+    let pack_fn = build_pack_fn(&args, &fn_decl_args, &size, &byte_order);
+    let unpack_fn = build_unpack_fn(&args_types, &size, false, &byte_order);
+    let byte_order_fns = build_byte_order_fns(&args, &fn_decl_args, &args_types, &byte_order);
+    let size_fn = build_size_fn(&size, Some(size.clone()));
+
+    quote! {{
+        #[derive(Debug)]
+        #[allow(non_camel_case_types)]
+        struct #struct_name;
+        #[allow(unused_imports)]
+        use std::io::{Result, Write, Read, Error, ErrorKind, Cursor};
 
-            #[allow(unused)] static TRUE_BUF: &[u8] = &[1];
-            #[allow(unused)] static FALSE_BUF: &[u8] = &[0];
+        #[allow(clippy::too_many_arguments)]
+        impl #struct_name {
+            #pack_fn
 
-            impl #struct_name {
-                #pack_fn
-                #pack_into_fn
-                #unpack_fn
-                #unpack_from_fn
-                #size_fn
+            #[allow(unused)]
+            fn pack_into_with<__E: structure::byteorder::ByteOrder, __W: Write>(&self, wtr: &mut __W, #fn_decl_args)
+                -> Result<()> {
+                let mut wtr = structure::bits::BitWriter::new(wtr, #lsb_first);
+                #writings
+                wtr.finish()
             }
 
-            #struct_name // Create structure instance
-        }};
+            #unpack_fn
 
-        output.into_string()
+            #[allow(unused)]
+            fn unpack_from_with<__E: structure::byteorder::ByteOrder, __R: Read>(&self, rdr: &mut __R)
+                -> Result<(#args_types)> {
+                let mut rdr = structure::bits::BitReader::new(rdr, #lsb_first);
+                #readings
+                Ok((#args))
+            }
+
+            #byte_order_fns
+            #size_fn
+
+            /// Return the size in bits, without the padding of the last byte
+            #[allow(unused)]
+            fn size_in_bits(&self) -> usize {
+                #size_in_bits
+            }
+        }
+
+        #struct_name // Create structure instance
+    }}
+}
+
+/// Return the byteorder type of an endianness
+fn endianness_tokens(endianness: &Endianness) -> Tokens {
+    match *endianness {
+        Endianness::Native => {
+            if cfg!(target_endian = "little") {
+                quote!(LittleEndian)
+            } else {
+                quote!(BigEndian)
+            }
+        }
+        Endianness::LittleEndian => quote!(LittleEndian),
+        Endianness::BigEndian => quote!(BigEndian),
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 enum Endianness {
     Native,
     LittleEndian,
     BigEndian,
 }
 
-fn build_pack_fn(args: &Tokens, fn_decl_args: &Tokens, size: usize) -> Tokens {
+/// A declaration of `structure_enum!`
+enum Declaration {
+    Enum(EnumDeclaration),
+    Layout(LayoutDeclaration),
+}
+
+/// A `structure_enum!` enum
+struct EnumDeclaration {
+    /// The attributes and the visibility of the enum, as Rust code
+    attrs: String,
+    visibility: String,
+    name: String,
+    tag_format: String,
+    variants: Vec<EnumVariant>,
+}
+
+/// A `structure_enum!` named layout, like `struct Header: "2H I";`
+struct LayoutDeclaration {
+    attrs: String,
+    visibility: String,
+    name: String,
+    format: String,
+}
+
+struct EnumVariant {
+    /// The attributes of the variant (like doc comments), as Rust code
+    attrs: String,
+    name: String,
+    tag: String,
+    format: String,
+}
+
+/// Split a `structure_enum!` input into string literals, identifiers (and numbers) and punctuation
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        let mut token = c.to_string();
+        if c == '"' {
+            loop {
+                match chars.next() {
+                    None => panic!("String literal must end with '\"'"),
+                    Some('\\') => {
+                        token.push('\\');
+                        token.extend(chars.next());
+                    }
+                    Some('"') => {
+                        token.push('"');
+                        break;
+                    }
+                    Some(c) => token.push(c),
+                }
+            }
+        } else if c.is_alphanumeric() || c == '_' {
+            while let Some(&c) = chars.peek() {
+                if !c.is_alphanumeric() && c != '_' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+        }
+        tokens.push(token);
+    }
+    tokens
+}
+
+/// Parse the attributes at the start of the tokens, as Rust code
+fn parse_attributes(tokens: &[String], i: &mut usize) -> String {
+    let mut attrs = String::new();
+    while tokens.get(*i).map(String::as_str) == Some("#") {
+        let mut depth = 0;
+        loop {
+            let token = tokens.get(*i).unwrap_or_else(|| panic!("Attribute must end with ']'"));
+            attrs.push_str(token);
+            attrs.push(' ');
+            *i += 1;
+            match token.as_str() {
+                "[" => depth += 1,
+                "]" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    attrs
+}
+
+/// Parse the input of `structure_enum!`: enums like `enum Message: "B" { Ping = 1 => "I", Quit = 2 => "" }`,
+/// and named layouts like `struct Header: "2H I";`
+fn parse_declarations(input: &str) -> Vec<Declaration> {
+    let tokens = tokenize(input);
+    let mut i = 0;
+    let mut declarations = vec![];
+    while i < tokens.len() {
+        declarations.push(parse_declaration(&tokens, &mut i));
+    }
+    declarations
+}
+
+/// Parse a single enum or named layout of a `structure_enum!`
+fn parse_declaration(tokens: &[String], position: &mut usize) -> Declaration {
+    let mut i = *position;
+    let expect = |i: &mut usize, expected: &str| {
+        if tokens.get(*i).map(String::as_str) != Some(expected) {
+            panic!("structure_enum!() expected '{}' (got '{}')", expected, tokens.get(*i).map_or("", String::as_str));
+        }
+        *i += 1;
+    };
+    // An identifier or a number
+    let word = |i: &mut usize, what: &str| -> String {
+        match tokens.get(*i) {
+            Some(token) if token.starts_with(|c: char| c.is_alphanumeric() || c == '_') => {
+                *i += 1;
+                token.clone()
+            }
+            _ => panic!("structure_enum!() expected {}", what),
+        }
+    };
+    let string_literal = |i: &mut usize, what: &str| -> String {
+        match tokens.get(*i) {
+            Some(token) if token.starts_with('"') => {
+                *i += 1;
+                trim_quotes(token).to_owned()
+            }
+            _ => panic!("structure_enum!() expected {} as a string literal", what),
+        }
+    };
+
+    let attrs = parse_attributes(tokens, &mut i);
+    let mut visibility = String::new();
+    if tokens.get(i).map(String::as_str) == Some("pub") {
+        visibility.push_str("pub");
+        i += 1;
+        if tokens.get(i).map(String::as_str) == Some("(") {
+            while let Some(token) = tokens.get(i) {
+                visibility.push_str(token);
+                i += 1;
+                if token == ")" {
+                    break;
+                }
+            }
+        }
+    }
+    if tokens.get(i).map(String::as_str) == Some("struct") {
+        i += 1;
+        let name = word(&mut i, "the name of the layout");
+        expect(&mut i, ":");
+        let format = string_literal(&mut i, "the format of the layout");
+        expect(&mut i, ";");
+        *position = i;
+        return Declaration::Layout(LayoutDeclaration { attrs, visibility, name, format });
+    }
+    expect(&mut i, "enum");
+    let name = word(&mut i, "the name of the enum");
+    expect(&mut i, ":");
+    let tag_format = string_literal(&mut i, "the format of the tag");
+    expect(&mut i, "{");
+    let mut variants = vec![];
+    while tokens.get(i).map(String::as_str) != Some("}") {
+        let attrs = parse_attributes(tokens, &mut i);
+        let name = word(&mut i, "the name of a variant");
+        expect(&mut i, "=");
+        let tag = word(&mut i, "the tag of a variant");
+        expect(&mut i, "=");
+        expect(&mut i, ">");
+        let format = string_literal(&mut i, "the format of a variant");
+        variants.push(EnumVariant { attrs, name, tag, format });
+        if tokens.get(i).map(String::as_str) != Some("}") {
+            expect(&mut i, ",");
+        }
+    }
+    expect(&mut i, "}");
+    *position = i;
+    Declaration::Enum(EnumDeclaration { attrs, visibility, name, tag_format, variants })
+}
+
+/// Build an enum whose variants are packed after a tag that tells them apart
+fn build_enum(declaration: &EnumDeclaration) -> Tokens {
+    let (tag_values, endianness) = format_to_values(&declaration.tag_format);
+    if tag_values.len() != 1 || !tag_values[0].is_unsigned_integer() || tag_values[0].type_name() == "u128" ||
+        tag_values[0].literal.is_some() {
+        panic!("The tag of an enum must be a single unsigned integer of at most 64 bits");
+    }
+    let tag_value = &tag_values[0];
+    let tag_type = Ident::from(tag_value.type_name().as_str());
+    let byte_order = endianness_tokens(&endianness);
+    let write_tag = write_scalar(tag_value, &Ident::from("tag"), &byte_order);
+    let read_tag = read_scalar(tag_value, &byte_order);
+    let write_tag_with = write_scalar(tag_value, &Ident::from("tag"), &quote!(__E));
+    let read_tag_with = read_scalar(tag_value, &quote!(__E));
+    let name = Ident::from(declaration.name.as_str());
+    let name_str = &declaration.name;
+
+    let mut variants = vec![];
+    let mut tags = vec![];
+    let mut tag_arms = vec![];
+    let mut pack_arms = vec![];
+    let mut pack_with_arms = vec![];
+    let mut unpack_arms = vec![];
+    let mut unpack_with_arms = vec![];
+    for variant in &declaration.variants {
+        let tag = parse_integer_literal(&variant.tag, tag_value);
+        if tags.contains(&tag.magnitude) {
+            panic!("Tag {} is used by more than one variant", variant.tag);
+        }
+        tags.push(tag.magnitude);
+        let tag = tag.to_tokens(tag_value.type_name());
+        let variant_name = Ident::from(variant.name.as_str());
+        let variant_attrs = Ident::from(variant.attrs.as_str());
+
+        // The variant's format has the byte order of the tag, unless it has its own (or is bit-level)
+        let format = match declaration.tag_format.chars().next() {
+            Some(c @ '@') | Some(c @ '=') | Some(c @ '<') | Some(c @ '>') | Some(c @ '!')
+                if !variant.format.starts_with(['@', '=', '<', '>', '!', '%']) => format!("{}{}", c, variant.format),
+            _ => variant.format.clone(),
+        };
+        let types = match format.strip_prefix('%') {
+            Some(bit_format) => bit_arg_types(&parse_bit_items(bit_format).0),
+            None => arg_types(&format_to_values(&format).0),
+        };
+        let structure = build_structure(&format);
+        tag_arms.push(quote! { #name::#variant_name { .. } => #tag, });
+        if types.is_empty() {
+            variants.push(quote! { #variant_attrs #variant_name, });
+            pack_arms.push(quote! {
+                #name::#variant_name => {
+                    let tag = #tag;
+                    #write_tag
+                    Ok(())
+                }
+            });
+            pack_with_arms.push(quote! {
+                #name::#variant_name => {
+                    let tag = #tag;
+                    #write_tag_with
+                    Ok(())
+                }
+            });
+            unpack_arms.push(quote! {
+                #tag => Ok(#name::#variant_name),
+            });
+            unpack_with_arms.push(quote! {
+                #tag => Ok(#name::#variant_name),
+            });
+            continue;
+        }
+
+        let args: Vec<Ident> = (1..=types.len()).map(|i| Ident::from(format!("_{}", i))).collect();
+        let field_types: Vec<Ident> = types.iter().map(|(_, result_type)| Ident::from(result_type.as_str())).collect();
+        let pack_args = owned_pack_args(&args, &types, &enum_args(&format));
+        let pack_with_args = pack_args.clone();
+        let (ref_args, ref_with_args) = (args.clone(), args.clone());
+        let (unpack_with_args, variant_args, variant_with_args) = (args.clone(), args.clone(), args.clone());
+        // Like a nested layout, a variant with its own byte order keeps it whatever the chosen byte order
+        let (pack_into_with, unpack_from_with) = if variant.format.starts_with(['@', '=', '<', '>', '!', '%']) {
+            (quote!(pack_into), quote!(unpack_from))
+        } else {
+            (quote!(pack_into_with::<__E, _>), quote!(unpack_from_with::<__E, _>))
+        };
+        variants.push(quote! { #variant_attrs #variant_name(#(#field_types,)*), });
+        pack_arms.push(quote! {
+            #name::#variant_name(#(ref #ref_args,)*) => {
+                let tag = #tag;
+                #write_tag
+                #structure.pack_into(wtr, #(#pack_args,)*)
+            }
+        });
+        pack_with_arms.push(quote! {
+            #name::#variant_name(#(ref #ref_with_args,)*) => {
+                let tag = #tag;
+                #write_tag_with
+                #structure.#pack_into_with(wtr, #(#pack_with_args,)*)
+            }
+        });
+        unpack_arms.push(quote! {
+            #tag => {
+                let (#(#args,)*) = #structure.unpack_from(rdr)?;
+                Ok(#name::#variant_name(#(#variant_args,)*))
+            }
+        });
+        unpack_with_arms.push(quote! {
+            #tag => {
+                let (#(#unpack_with_args,)*) = #structure.#unpack_from_with(rdr)?;
+                Ok(#name::#variant_name(#(#variant_with_args,)*))
+            }
+        });
+    }
+
+    let pack_fns = quote! {
+        pub fn pack(&self) -> std::io::Result<Vec<u8>> {
+            let mut wtr = Vec::new();
+            self.pack_into(&mut wtr)?;
+            Ok(wtr)
+        }
+
+        pub fn pack_with<__E: structure::byteorder::ByteOrder>(&self) -> std::io::Result<Vec<u8>> {
+            let mut wtr = Vec::new();
+            self.pack_into_with::<__E, _>(&mut wtr)?;
+            Ok(wtr)
+        }
+
+        pub fn pack_into<__W: std::io::Write>(&self, wtr: &mut __W) -> std::io::Result<()> {
+            #[allow(unused_imports)]
+            use structure::byteorder::{WriteBytesExt, BigEndian, LittleEndian};
+            match *self {
+                #(#pack_arms)*
+            }
+        }
+
+        pub fn pack_into_with<__E: structure::byteorder::ByteOrder, __W: std::io::Write>(&self, wtr: &mut __W)
+            -> std::io::Result<()> {
+            #[allow(unused_imports)]
+            use structure::byteorder::WriteBytesExt;
+            match *self {
+                #(#pack_with_arms)*
+            }
+        }
+    };
+    let unpack_fns = quote! {
+        pub fn unpack<__B: AsRef<[u8]>>(buf: __B) -> std::io::Result<#name> {
+            use std::io::{Cursor, Error, ErrorKind};
+            let mut rdr = Cursor::new(buf);
+            let value = #name::unpack_from(&mut rdr)?;
+            let buf_length = rdr.get_ref().as_ref().len();
+            if rdr.position() != buf_length as u64 {
+                let msg = format!("Buffer length does not match the format \
+                    (unpacked size: {}, actual size: {})", rdr.position(), buf_length);
+                return Err(Error::new(ErrorKind::InvalidInput, msg))
+            }
+            Ok(value)
+        }
+
+        pub fn unpack_with<__E: structure::byteorder::ByteOrder, __B: AsRef<[u8]>>(buf: __B)
+            -> std::io::Result<#name> {
+            use std::io::{Cursor, Error, ErrorKind};
+            let mut rdr = Cursor::new(buf);
+            let value = #name::unpack_from_with::<__E, _>(&mut rdr)?;
+            let buf_length = rdr.get_ref().as_ref().len();
+            if rdr.position() != buf_length as u64 {
+                let msg = format!("Buffer length does not match the format \
+                    (unpacked size: {}, actual size: {})", rdr.position(), buf_length);
+                return Err(Error::new(ErrorKind::InvalidInput, msg))
+            }
+            Ok(value)
+        }
+
+        pub fn unpack_from<__R: std::io::Read>(rdr: &mut __R) -> std::io::Result<#name> {
+            #[allow(unused_imports)]
+            use std::io::{Error, ErrorKind};
+            #[allow(unused_imports)]
+            use structure::byteorder::{ReadBytesExt, BigEndian, LittleEndian};
+            let tag = #read_tag;
+            match tag {
+                #(#unpack_arms)*
+                tag => Err(Error::new(ErrorKind::InvalidData, structure::error::UnknownTag {
+                    name: #name_str,
+                    tag: tag as u64,
+                })),
+            }
+        }
+
+        pub fn unpack_from_with<__E: structure::byteorder::ByteOrder, __R: std::io::Read>(rdr: &mut __R)
+            -> std::io::Result<#name> {
+            #[allow(unused_imports)]
+            use std::io::{Error, ErrorKind};
+            #[allow(unused_imports)]
+            use structure::byteorder::ReadBytesExt;
+            let tag = #read_tag_with;
+            match tag {
+                #(#unpack_with_arms)*
+                tag => Err(Error::new(ErrorKind::InvalidData, structure::error::UnknownTag {
+                    name: #name_str,
+                    tag: tag as u64,
+                })),
+            }
+        }
+    };
+
+    let attrs = Ident::from(declaration.attrs.as_str());
+    let visibility = Ident::from(declaration.visibility.as_str());
+    quote! {
+        #attrs
+        #visibility enum #name {
+            #(#variants)*
+        }
+
+        #[allow(unused)]
+        impl #name {
+            /// Return the tag of the variant
+            pub fn tag(&self) -> #tag_type {
+                match *self {
+                    #(#tag_arms)*
+                }
+            }
+
+            #pack_fns
+            #unpack_fns
+        }
+    }
+}
+
+/// Build the arguments that pack owned fields, which are bound by reference to `args`
+fn owned_pack_args(args: &[Ident], types: &[(String, String)], enum_args: &[bool]) -> Vec<Tokens> {
+    // The fields are owned, while packing borrows buffers, arrays and user-defined types (and takes
+    // enums by value, which may not be `Copy`)
+    args.iter().zip(types).zip(enum_args).map(|((arg, (decl_type, result_type)), &is_enum)| {
+        if is_enum {
+            quote!(#arg.clone())
+        } else if decl_type == result_type {
+            quote!(*#arg)
+        } else if decl_type.starts_with("Option<&[") {
+            quote!(#arg.as_deref())
+        } else if decl_type.starts_with("Option<") {
+            quote!(#arg.as_ref())
+        } else {
+            quote!(#arg)
+        }
+    }).collect()
+}
+
+/// Return whether each argument of a format is enum-typed
+fn enum_args(format: &str) -> Vec<bool> {
+    if let Some(bit_format) = format.strip_prefix('%') {
+        return vec![false; bit_arg_types(&parse_bit_items(bit_format).0).len()];
+    }
+    values_enum_args(&format_to_values(format).0)
+}
+
+/// Return whether each argument of the given values is enum-typed
+fn values_enum_args(values: &[StructValue]) -> Vec<bool> {
+    let mut enum_args = vec![];
+    for v in values {
+        enum_args.extend(std::iter::repeat_n(v.enum_type.is_some(), v.arg_count()));
+    }
+    enum_args
+}
+
+/// Build a tuple struct of the fields of a named layout, which is also a user-defined type
+fn build_layout(declaration: &LayoutDeclaration) -> Tokens {
+    let (types, size) = match declaration.format.strip_prefix('%') {
+        Some(bit_format) => {
+            let items = parse_bit_items(bit_format).0;
+            let size = items.iter().map(|item| item.width).sum::<usize>().div_ceil(8);
+            (bit_arg_types(&items), quote!(#size))
+        }
+        None => {
+            let values = format_to_values(&declaration.format).0;
+            if is_variable_size(&values) {
+                panic!("The layout {} must have a fixed size", declaration.name);
+            }
+            (arg_types(&values), build_size(calc_size(&values), &values, false))
+        }
+    };
+    let structure = build_structure(&declaration.format);
+    let name = Ident::from(declaration.name.as_str());
+    let attrs = Ident::from(declaration.attrs.as_str());
+    let visibility = Ident::from(declaration.visibility.as_str());
+
+    let args: Vec<Ident> = (1..=types.len()).map(|i| Ident::from(format!("_{}", i))).collect();
+    let field_types: Vec<Ident> = types.iter().map(|(_, result_type)| Ident::from(result_type.as_str())).collect();
+    let field_visibility: Vec<&Ident> = field_types.iter().map(|_| &visibility).collect();
+    let pack_args = owned_pack_args(&args, &types, &enum_args(&declaration.format));
+    let (ref_args, ref_with_args) = (args.clone(), args.clone());
+    let pack_with_args = pack_args.clone();
+    let (unpack_args, unpack_from_args) = (args.clone(), args.clone());
+    let (unpack_with_args, unpack_from_with_args) = (args.clone(), args.clone());
+    let (struct_args, struct_from_args) = (args.clone(), args.clone());
+    let (struct_with_args, struct_from_with_args) = (args.clone(), args.clone());
+
+    // A layout whose format has its own byte order keeps it wherever it is nested, otherwise it takes
+    // the byte order of the format it is nested in
+    let (encode, decode) = if declaration.format.starts_with(['@', '=', '<', '>', '!', '%']) {
+        (quote!(self.pack_into(wtr)), quote!(#name::unpack_from(rdr)))
+    } else {
+        (quote!(self.pack_into_with::<__E, _>(wtr)), quote!(#name::unpack_from_with::<__E, _>(rdr)))
+    };
+    quote! {
+        #attrs
+        #visibility struct #name(#(#field_visibility #field_types,)*);
+
+        #[allow(unused)]
+        impl #name {
+            pub fn pack(&self) -> std::io::Result<Vec<u8>> {
+                let mut wtr = Vec::with_capacity(#size);
+                self.pack_into(&mut wtr)?;
+                Ok(wtr)
+            }
+
+            pub fn pack_with<__E: structure::byteorder::ByteOrder>(&self) -> std::io::Result<Vec<u8>> {
+                let mut wtr = Vec::with_capacity(#size);
+                self.pack_into_with::<__E, _>(&mut wtr)?;
+                Ok(wtr)
+            }
+
+            pub fn pack_into<__W: std::io::Write>(&self, wtr: &mut __W) -> std::io::Result<()> {
+                let #name(#(ref #ref_args,)*) = *self;
+                #structure.pack_into(wtr, #(#pack_args,)*)
+            }
+
+            pub fn pack_into_with<__E: structure::byteorder::ByteOrder, __W: std::io::Write>(&self, wtr: &mut __W)
+                -> std::io::Result<()> {
+                let #name(#(ref #ref_with_args,)*) = *self;
+                #structure.pack_into_with::<__E, _>(wtr, #(#pack_with_args,)*)
+            }
+
+            pub fn unpack<__B: AsRef<[u8]>>(buf: __B) -> std::io::Result<#name> {
+                let (#(#unpack_args,)*) = #structure.unpack(buf)?;
+                Ok(#name(#(#struct_args,)*))
+            }
+
+            pub fn unpack_with<__E: structure::byteorder::ByteOrder, __B: AsRef<[u8]>>(buf: __B)
+                -> std::io::Result<#name> {
+                let (#(#unpack_with_args,)*) = #structure.unpack_with::<__E, _>(buf)?;
+                Ok(#name(#(#struct_with_args,)*))
+            }
+
+            pub fn unpack_from<__R: std::io::Read>(rdr: &mut __R) -> std::io::Result<#name> {
+                let (#(#unpack_from_args,)*) = #structure.unpack_from(rdr)?;
+                Ok(#name(#(#struct_from_args,)*))
+            }
+
+            pub fn unpack_from_with<__E: structure::byteorder::ByteOrder, __R: std::io::Read>(rdr: &mut __R)
+                -> std::io::Result<#name> {
+                let (#(#unpack_from_with_args,)*) = #structure.unpack_from_with::<__E, _>(rdr)?;
+                Ok(#name(#(#struct_from_with_args,)*))
+            }
+        }
+
+        impl structure::format::Format for #name {
+            const SIZE: usize = #size;
+
+            fn encode<__E: structure::byteorder::ByteOrder, __W: std::io::Write>(&self, wtr: &mut __W) -> std::io::Result<()> {
+                #encode
+            }
+
+            fn decode<__E: structure::byteorder::ByteOrder, __R: std::io::Read>(rdr: &mut __R) -> std::io::Result<#name> {
+                #decode
+            }
+        }
+    }
+}
+
+fn build_pack_fn(args: &Tokens, fn_decl_args: &Tokens, size: &Tokens, endianness: &Tokens) -> Tokens {
     quote! {
         #[allow(unused)]
         fn pack(&self, #fn_decl_args) -> Result<Vec<u8>> {
+            self.pack_with::<#endianness>(#args)
+        }
+
+        #[allow(unused)]
+        fn pack_with<__E: structure::byteorder::ByteOrder>(&self, #fn_decl_args) -> Result<Vec<u8>> {
             let mut wtr = Vec::with_capacity(#size);
-            self.pack_into(&mut wtr, #args)?;
+            self.pack_into_with::<__E, _>(&mut wtr, #args)?;
             Ok(wtr)
         }
     }
 }
 
-fn build_pack_into_fn(values: &[StructValue], fn_decl_args: &Tokens, endianness: &Tokens) -> Tokens {
-    // Pack each argument
+/// Build `pack_into` and `unpack_from`, which use the byte order of the format
+fn build_byte_order_fns(args: &Tokens, fn_decl_args: &Tokens, args_types: &Tokens, endianness: &Tokens) -> Tokens {
+    quote! {
+        #[allow(unused)]
+        fn pack_into<__W: Write>(&self, wtr: &mut __W, #fn_decl_args) -> Result<()> {
+            self.pack_into_with::<#endianness, _>(wtr, #args)
+        }
+
+        #[allow(unused)]
+        fn unpack_from<__R: Read>(&self, rdr: &mut __R) -> Result<(#args_types)> {
+            self.unpack_from_with::<#endianness, _>(rdr)
+        }
+    }
+}
+
+/// Build the code that writes the fields to `wtr`, from the arguments `_1`, `_2`...
+fn build_writings(values: &[StructValue], endianness: &Tokens) -> Tokens {
     let mut writings = Tokens::new();
     let mut arg_index = 0;
-    for value in values {
-        let writing = match *value.kind() {
-            ValueKind::Number | ValueKind::Boolean | ValueKind::Pointer => {
+    for (index, value) in values.iter().enumerate() {
+        let writing = if value.literal.is_some() {
+            build_literal_writing(value, endianness)
+        } else if !value.counts.is_empty() {
+            build_count_writing(values, value, endianness)
+        } else if let Some(ref count) = value.count {
+            arg_index += 1;
+            let current_arg = Ident::from(format!("_{}", arg_index));
+            build_counted_writing(value, count, &current_arg, endianness)
+        } else { match *value.kind() {
+            ValueKind::Number | ValueKind::Boolean | ValueKind::Pointer | ValueKind::HalfFloat |
+            ValueKind::NativeSize | ValueKind::Char | ValueKind::Varint(_) | ValueKind::SizedInteger(_) |
+            ValueKind::UserType => {
                 let mut tokens = Tokens::new();
-                for _ in 0..value.repeat() {
-                    arg_index += 1;
-                    let current_arg = Ident::from(format!("_{}", arg_index));
-                    if *value.kind() == ValueKind::Number {
-                        let byteorder_fn = Ident::from(format!("write_{}", value.type_name()));
-                        match value.type_name().as_str() {
-                            "u8" | "i8" => {
-                                tokens.append(quote! {wtr.#byteorder_fn(#current_arg)?;});
-                            }
-                            _ => {
-                                tokens.append(quote! {wtr.#byteorder_fn::<#endianness>(#current_arg)?;});
-                            }
-                        }
-                    } else if *value.kind() == ValueKind::Boolean {
-                        tokens.append(quote! {
-                            let buf = if #current_arg { TRUE_BUF } else { FALSE_BUF };
-                            wtr.write(buf)?;
-                        });
-                    } else {
-                        let size = mem::size_of::<usize>();
-                        let integer_type = Ident::from(format!("u{}", size * 8));
-                        let byteorder_fn = Ident::from(format!("write_u{}", size * 8));
-                        tokens.append(quote! {
-                            let v = #current_arg as #integer_type;
-                            wtr.#byteorder_fn::<#endianness>(v)?;
-                        });
+                if let Some(ref bitfields) = value.bitfields {
+                    tokens.append(build_bitfields_writing(value, bitfields, &mut arg_index, endianness));
+                } else {
+                    for _ in 0..value.repeat() {
+                        arg_index += 1;
+                        let current_arg = Ident::from(format!("_{}", arg_index));
+                        tokens.append(write_scalar(value, &current_arg, endianness));
                     }
                 }
                 tokens
             }
+
             ValueKind::Buffer | ValueKind::FixedBuffer => {
                 arg_index += 1;
                 let current_arg = Ident::from(format!("_{}", arg_index));
@@ -146,79 +902,285 @@ fn build_pack_into_fn(values: &[StructValue], fn_decl_args: &Tokens, endianness:
                 }
                 tokens
             }
-            ValueKind::Padding => {
-                let number = value.repeat();
+            ValueKind::PrefixedBuffer(prefix_type) => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                let prefix_type_ident = Ident::from(prefix_type);
+                let writing = write_number(prefix_type, &quote!(#current_arg.len() as #prefix_type_ident), endianness);
                 quote! {
-                    wtr.write_all(&[0; #number])?;
+                    if #current_arg.len() as u64 > #prefix_type_ident::MAX as u64 {
+                        let msg = format!("Buffer is too long for its length prefix \
+                            (prefix type: {}, actual size: {})", #prefix_type, #current_arg.len());
+                        return Err(Error::new(ErrorKind::InvalidInput, msg));
+                    }
+                    #writing
+                    wtr.write_all(#current_arg)?;
                 }
             }
-        };
-        writings.append(writing);
-    }
-
-    quote! {
-        #[allow(unused)]
-        fn pack_into<T: Write>(&self, wtr: &mut T, #fn_decl_args) -> Result<()> {
-            #writings
-            Ok(())
-        }
-    }
-}
-
-fn build_unpack_fn(args_types: &Tokens, size: usize) -> Tokens {
-    quote! {
-        #[allow(unused)]
-        fn unpack<T: AsRef<[u8]>>(&self, buf: T) -> Result<(#args_types,)> {
-            if buf.as_ref().len() != #size {
-                let msg = format!("Buffer length does not match the format \
+            ValueKind::CString | ValueKind::FixedCString => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                let mut tokens = quote! {
+                    if #current_arg.contains(&0) {
+                        let msg = "C string contains a NUL byte".to_owned();
+                        return Err(Error::new(ErrorKind::InvalidInput, msg));
+                    }
+                };
+                if *value.kind() == ValueKind::CString {
+                    tokens.append(quote! {
+                        wtr.write_all(#current_arg)?;
+                        wtr.write_u8(0)?;
+                    });
+                } else {
+                    // The value may fill the whole field, without a terminating NUL
+                    let field_length = value.repeat();
+                    tokens.append(quote! {
+                        if #current_arg.len() > #field_length {
+                            let msg = format!("C string is longer than its field \
+                                (field size in format: {}, actual size: {})", #field_length, #current_arg.len());
+                            return Err(Error::new(ErrorKind::InvalidInput, msg));
+                        }
+                        wtr.write_all(#current_arg)?;
+                        wtr.write_all(&vec![0; #field_length - #current_arg.len()])?;
+                    });
+                }
+                tokens
+            }
+            ValueKind::PascalString => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                let field_length = value.repeat();
+                if field_length == 0 {
+                    Tokens::new()
+                } else {
+                    // The first byte holds the length, so the data is truncated to fit in the rest
+                    let max_length = std::cmp::min(field_length - 1, 255);
+                    quote! {
+                        let length = std::cmp::min(#current_arg.len(), #max_length);
+                        wtr.write_u8(length as u8)?;
+                        wtr.write_all(&#current_arg[..length])?;
+                        wtr.write_all(&vec![0; #field_length - 1 - length])?;
+                    }
+                }
+            }
+            ValueKind::Rest => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                quote! {
+                    wtr.write_all(#current_arg)?;
+                }
+            }
+            ValueKind::Terminated => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                build_terminated_writing(value.array.as_ref().unwrap(), &current_arg, endianness)
+            }
+            ValueKind::ByteLiteral(_) => build_literal_writing(value, endianness),
+            ValueKind::Checksum(_) => {
+                // Written as zeros, and filled in once the whole record is packed
+                let offset = Ident::from(format!("checksum_offset_{}", index));
+                let width = type_size(value.type_name());
+                quote! {
+                    let #offset = wtr.len();
+                    wtr.write_all(&[0; #width])?;
+                }
+            }
+            ValueKind::Padding => {
+                let number = value.repeat();
+                quote! {
+                    wtr.write_all(&[0; #number])?;
+                }
+            }
+            ValueKind::Align(alignment) => quote! {
+                let padding = (#alignment - wtr.len() % #alignment) % #alignment;
+                wtr.write_all(&vec![0; padding])?;
+            },
+        }};
+        if let Some(ref condition) = value.condition {
+            // A conditional field has a single argument, which must be given exactly when it is present
+            let current_arg = Ident::from(format!("_{}", arg_index));
+            let test = condition_test(values, condition);
+            writings.append(quote! {
+                match (#test, #current_arg) {
+                    (true, Some(#current_arg)) => { #writing }
+                    (false, None) => {}
+                    (present, _) => {
+                        let msg = format!("Conditional field must be given exactly when its condition holds \
+                            (condition: {}, given: {})", present, !present);
+                        return Err(Error::new(ErrorKind::InvalidInput, msg));
+                    }
+                }
+            });
+            continue;
+        }
+        writings.append(writing);
+    }
+    writings
+}
+
+fn build_pack_into_fn(values: &[StructValue], fn_decl_args: &Tokens, endianness: &Tokens, size: &Tokens) -> Tokens {
+    let writings = build_writings(values, endianness);
+
+    if !values.iter().any(|v| matches!(*v.kind(), ValueKind::Checksum(_) | ValueKind::Align(_))) {
+        return quote! {
+            #[allow(unused)]
+            fn pack_into_with<__E: ByteOrder, __W: Write>(&self, wtr: &mut __W, #fn_decl_args) -> Result<()> {
+                #writings
+                Ok(())
+            }
+        };
+    }
+
+    // Checksums are computed in the order of their fields
+    let mut patches = Tokens::new();
+    for (index, value) in values.iter().enumerate() {
+        if let ValueKind::Checksum(ref checksum) = *value.kind() {
+            let offset = Ident::from(format!("checksum_offset_{}", index));
+            let (start, end) = checksum_range(checksum, &offset, &quote!(wtr.len()));
+            let checksum_fn = checksum_fn(checksum, endianness);
+            let patch = match value.type_name().as_str() {
+                "u8" => quote! { wtr[#offset] = checksum; },
+                type_name => {
+                    let width = type_size(type_name);
+                    let byteorder_fn = Ident::from(format!("write_{}", type_name));
+                    quote! { #endianness::#byteorder_fn(&mut wtr[#offset..#offset + #width], checksum); }
+                }
+            };
+            patches.append(quote! {
+                let (start, end) = (#start, #end);
+                let checksum = match wtr.get(start..end) {
+                    Some(covered) => #checksum_fn(covered),
+                    None => {
+                        let msg = format!("Checksum range is outside of the record \
+                            (range: {}..{}, record size: {})", start, end, wtr.len());
+                        return Err(Error::new(ErrorKind::InvalidInput, msg));
+                    }
+                };
+                #patch
+            });
+        }
+    }
+
+    // Pack into a buffer first, since checksums may cover fields that come after them (and alignments
+    // depend on the offset)
+    quote! {
+        #[allow(unused)]
+        fn pack_into_with<__E: ByteOrder, __W: Write>(&self, wtr: &mut __W, #fn_decl_args) -> Result<()> {
+            let mut record = Vec::with_capacity(#size);
+            let output = wtr;
+            let wtr = &mut record;
+            #writings
+            #patches
+            output.write_all(wtr)
+        }
+    }
+}
+
+/// Return the start and the end of the bytes that a checksum covers
+fn checksum_range(checksum: &ChecksumSpec, offset: &Ident, record_length: &Tokens) -> (usize, Tokens) {
+    let end = match checksum.end {
+        RangeEnd::Field => quote!(#offset),
+        RangeEnd::Record => record_length.clone(),
+        RangeEnd::Offset(end) => quote!(#end),
+    };
+    (checksum.start, end)
+}
+
+/// Return the function that computes a checksum
+fn checksum_fn(checksum: &ChecksumSpec, endianness: &Tokens) -> Tokens {
+    let name = Ident::from(checksum.algorithm);
+    match checksum.algorithm {
+        "internet" => quote!(structure::checksum::#name::<#endianness>),
+        _ => quote!(structure::checksum::#name),
+    }
+}
+
+fn build_unpack_fn(args_types: &Tokens, size: &Tokens, variable_size: bool, endianness: &Tokens) -> Tokens {
+    let unpack = quote! {
+        #[allow(unused)]
+        fn unpack<__B: AsRef<[u8]>>(&self, buf: __B) -> Result<(#args_types)> {
+            self.unpack_with::<#endianness, _>(buf)
+        }
+    };
+    if variable_size {
+        // The buffer must hold at least the fixed-size fields, and nothing may be left after unpacking
+        return quote! {
+            #unpack
+
+            #[allow(unused)]
+            fn unpack_with<__E: structure::byteorder::ByteOrder, __B: AsRef<[u8]>>(&self, buf: __B)
+                -> Result<(#args_types)> {
+                if buf.as_ref().len() < #size {
+                    let msg = format!("Buffer is smaller than the format \
+                        (minimum format size: {}, actual size: {})", #size, buf.as_ref().len());
+                    return Err(Error::new(ErrorKind::InvalidInput, msg))
+                }
+                let mut rdr = Cursor::new(buf);
+                let values = self.unpack_from_with::<__E, _>(&mut rdr)?;
+                let buf_length = rdr.get_ref().as_ref().len();
+                if rdr.position() != buf_length as u64 {
+                    let msg = format!("Buffer length does not match the format \
+                        (unpacked size: {}, actual size: {})", rdr.position(), buf_length);
+                    return Err(Error::new(ErrorKind::InvalidInput, msg))
+                }
+                Ok(values)
+            }
+        };
+    }
+    quote! {
+        #unpack
+
+        #[allow(unused)]
+        fn unpack_with<__E: structure::byteorder::ByteOrder, __B: AsRef<[u8]>>(&self, buf: __B) -> Result<(#args_types)> {
+            if buf.as_ref().len() != #size {
+                let msg = format!("Buffer length does not match the format \
                     (format size: {}, actual size: {}", #size, buf.as_ref().len());
                 return Err(Error::new(ErrorKind::InvalidInput, msg))
             }
             let mut rdr = Cursor::new(buf);
-            self.unpack_from(&mut rdr)
+            self.unpack_from_with::<__E, _>(&mut rdr)
         }
     }
 }
 
-fn build_unpack_from_fn(values: &[StructValue], args: &Tokens, args_types: &Tokens, endianness: &Tokens) -> Tokens {
+/// Build the code that reads the fields from `rdr`, into the variables `_1`, `_2`...
+fn build_readings(values: &[StructValue], endianness: &Tokens) -> Tokens {
     let mut readings = Tokens::new();
     let mut arg_index = 0;
-    for value in values {
-        let reading = match *value.kind() {
-            ValueKind::Number | ValueKind::Boolean | ValueKind::Pointer => {
+    for (index, value) in values.iter().enumerate() {
+        let reading = if value.literal.is_some() {
+            build_literal_check(value, endianness)
+        } else if !value.counts.is_empty() {
+            let count = Ident::from(format!("count_{}", index));
+            let reading = read_scalar(value, endianness);
+            quote! { let #count = #reading; }
+        } else if let Some(ref count) = value.count {
+            arg_index += 1;
+            let current_arg = Ident::from(format!("_{}", arg_index));
+            build_counted_reading(values, value, count, &current_arg, endianness)
+        } else { match *value.kind() {
+            ValueKind::Number | ValueKind::Boolean | ValueKind::Pointer | ValueKind::HalfFloat |
+            ValueKind::NativeSize | ValueKind::Char | ValueKind::Varint(_) | ValueKind::SizedInteger(_) |
+            ValueKind::UserType => {
                 let mut tokens = Tokens::new();
-                for _ in 0..value.repeat() {
-                    arg_index += 1;
-                    let current_arg = Ident::from(format!("_{}", arg_index));
-                    if *value.kind() == ValueKind::Number {
-                        let byteorder_fn = Ident::from(format!("read_{}", value.type_name()));
-                        match value.type_name().as_str() {
-                            "u8" | "i8" => {
-                                tokens.append(quote! { let #current_arg = rdr.#byteorder_fn()?;});
-                            }
-                            _ => {
-                                tokens.append(quote! { let #current_arg = rdr.#byteorder_fn::<#endianness>()?;});
+                if let Some(ref bitfields) = value.bitfields {
+                    tokens.append(build_bitfields_reading(value, bitfields, &mut arg_index, endianness));
+                } else {
+                    for _ in 0..value.repeat() {
+                        arg_index += 1;
+                        let current_arg = Ident::from(format!("_{}", arg_index));
+                        let reading = read_scalar(value, endianness);
+                        match value.enum_type {
+                            Some(ref enum_type) => {
+                                tokens.append(build_enum_conversion(value, enum_type, arg_index, &reading));
                             }
+                            None => tokens.append(quote! { let #current_arg = #reading; }),
                         }
-                    } else if *value.kind() == ValueKind::Boolean {
-                        tokens.append(quote! {
-                            let #current_arg = rdr.read_u8()?;
-                            let #current_arg = #current_arg != 0; // 0 is false
-                        });
-                    } else {
-                        let pointer_type = Ident::from(value.type_name().as_str());
-                        let size = mem::size_of::<usize>();
-                        let byteorder_fn = Ident::from(format!("read_u{}", size * 8));
-                        tokens.append(quote! {
-                            let #current_arg = {
-                                let v = rdr.#byteorder_fn::<#endianness>()?;
-                                v as #pointer_type
-                            };
-                        });
                     }
                 }
                 tokens
             }
+
             ValueKind::Buffer | ValueKind::FixedBuffer => {
                 arg_index += 1;
                 let current_arg = Ident::from(format!("_{}", arg_index));
@@ -228,99 +1190,1085 @@ fn build_unpack_from_fn(values: &[StructValue], args: &Tokens, args_types: &Toke
                     rdr.read_exact(&mut #current_arg)?;
                 }
             }
+            ValueKind::PrefixedBuffer(prefix_type) => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                let reading = read_number(prefix_type, endianness);
+                // Don't trust the prefix for allocating the buffer up front
+                quote! {
+                    let length = #reading as u64;
+                    let mut #current_arg = vec![];
+                    rdr.by_ref().take(length).read_to_end(&mut #current_arg)?;
+                    if (#current_arg.len() as u64) < length {
+                        return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+                    }
+                }
+            }
+            ValueKind::CString => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                quote! {
+                    let mut #current_arg = vec![];
+                    loop {
+                        match rdr.read_u8()? {
+                            0 => break,
+                            b => #current_arg.push(b),
+                        }
+                    }
+                }
+            }
+            ValueKind::FixedCString => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                let field_length = value.repeat();
+                quote! {
+                    let mut #current_arg = vec![0; #field_length];
+                    rdr.read_exact(&mut #current_arg)?;
+                    if let Some(nul_position) = #current_arg.iter().position(|&b| b == 0) {
+                        #current_arg.truncate(nul_position);
+                    }
+                }
+            }
+            ValueKind::PascalString => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                let field_length = value.repeat();
+                if field_length == 0 {
+                    quote! { let #current_arg = vec![]; }
+                } else {
+                    quote! {
+                        let length = rdr.read_u8()? as usize;
+                        let mut #current_arg = vec![0; #field_length - 1];
+                        rdr.read_exact(&mut #current_arg)?;
+                        #current_arg.truncate(length);
+                    }
+                }
+            }
+            ValueKind::Rest => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                quote! {
+                    let mut #current_arg = vec![];
+                    rdr.read_to_end(&mut #current_arg)?;
+                }
+            }
+            ValueKind::Terminated => {
+                arg_index += 1;
+                let current_arg = Ident::from(format!("_{}", arg_index));
+                build_terminated_reading(value.array.as_ref().unwrap(), &current_arg, endianness)
+            }
+            ValueKind::ByteLiteral(_) => build_literal_check(value, endianness),
+            ValueKind::Checksum(_) => {
+                let offset = Ident::from(format!("checksum_offset_{}", index));
+                let checksum = Ident::from(format!("checksum_{}", index));
+                let reading = read_number(value.type_name(), endianness);
+                quote! {
+                    let #offset = rdr.position() as usize;
+                    let #checksum = #reading;
+                }
+            }
             ValueKind::Padding => {
                 let number = value.repeat();
                 quote! {
                     rdr.read_exact(&mut [0; #number])?;
                 }
             }
+            ValueKind::Align(alignment) => {
+                let alignment = alignment as u64;
+                quote! {
+                    let padding = (#alignment - rdr.position() % #alignment) % #alignment;
+                    rdr.read_exact(&mut vec![0; padding as usize])?;
+                }
+            }
+        }};
+        if let Some(ref condition) = value.condition {
+            let current_arg = Ident::from(format!("_{}", arg_index));
+            let test = condition_test(values, condition);
+            readings.append(quote! {
+                let #current_arg = if #test {
+                    #reading
+                    Some(#current_arg)
+                } else {
+                    None
+                };
+            });
+            continue;
+        }
+        readings.append(reading);
+    }
+    readings
+}
+
+fn build_unpack_from_fn(values: &[StructValue], args: &Tokens, args_types: &Tokens, endianness: &Tokens) -> Tokens {
+    let readings = build_readings(values, endianness);
+
+    // Verify the checksums once the whole record is read
+    let mut verifications = Tokens::new();
+    let checksums: Vec<(usize, &StructValue)> = values.iter().enumerate()
+        .filter(|&(_, v)| matches!(*v.kind(), ValueKind::Checksum(_)))
+        .collect();
+    for (i, &(index, value)) in checksums.iter().enumerate() {
+        if let ValueKind::Checksum(ref checksum) = *value.kind() {
+            let offset = Ident::from(format!("checksum_offset_{}", index));
+            let actual = Ident::from(format!("checksum_{}", index));
+            let (start, end) = checksum_range(checksum, &offset, &quote!(rdr.record().len()));
+            let checksum_fn = checksum_fn(checksum, endianness);
+            let algorithm = Ident::from(match checksum.algorithm {
+                "crc32" => "Crc32",
+                "crc16" => "Crc16",
+                "internet" => "Internet",
+                _ => "Xor8",
+            });
+            // When packing, the field itself and the checksums after it were still zeros
+            let mut clears = Tokens::new();
+            for &(later_index, later_value) in &checksums[i..] {
+                let later_offset = Ident::from(format!("checksum_offset_{}", later_index));
+                let width = type_size(later_value.type_name());
+                clears.append(quote! {
+                    structure::checksum::clear_field(&mut covered, start, #later_offset, #width);
+                });
+            }
+            verifications.append(quote! {
+                let (start, end) = (#start, #end);
+                let mut covered = match rdr.record().get(start..end) {
+                    Some(covered) => covered.to_vec(),
+                    None => {
+                        let msg = format!("Checksum range is outside of the record \
+                            (range: {}..{}, record size: {})", start, end, rdr.record().len());
+                        return Err(Error::new(ErrorKind::InvalidData, msg));
+                    }
+                };
+                #clears
+                let expected = #checksum_fn(&covered);
+                if expected != #actual {
+                    return Err(Error::new(ErrorKind::InvalidData, structure::error::ChecksumMismatch {
+                        offset: #offset as u64,
+                        algorithm: structure::error::ChecksumAlgorithm::#algorithm,
+                        expected: u32::from(expected),
+                        actual: u32::from(#actual),
+                    }));
+                }
+            });
+        }
+    }
+
+    // Count (or record, for checksums) the bytes that are read if any field needs its offset
+    let counter = if !checksums.is_empty() {
+        quote! {
+            let mut rdr = structure::io::Recorder::new(rdr);
+            let rdr = &mut rdr;
+        }
+    } else if values.iter().any(|v| v.is_implicit() || v.enum_type.is_some() || matches!(v.kind, ValueKind::Align(_))) {
+        quote! {
+            let mut rdr = structure::io::Counter::new(rdr);
+            let rdr = &mut rdr;
+        }
+    } else {
+        Tokens::new()
+    };
+
+    quote! {
+        #[allow(unused)]
+        fn unpack_from_with<__E: ByteOrder, __R: Read>(&self, rdr: &mut __R) -> Result<(#args_types)> {
+            #counter
+            #readings
+            #verifications
+            Ok((#args))
+        }
+    }
+}
+
+/// Build `unpack_from_seek`, which also decodes the user-defined types at the offsets that fields hold
+/// (relative to `base`), if there are such fields
+fn build_unpack_from_seek_fn(values: &[StructValue], args: &Tokens, endianness: &Tokens) -> Tokens {
+    let arg_indices = arg_indices(values);
+    let mut types: Vec<String> = arg_types(values).into_iter().map(|(_, result_type)| result_type).collect();
+    let mut resolutions = Tokens::new();
+    for (value, &arg_index) in values.iter().zip(&arg_indices) {
+        if let Some(ref target) = value.target {
+            types[arg_index - 1] = target.clone();
+            let arg = Ident::from(format!("_{}", arg_index));
+            let target = Ident::from(target.as_str());
+            resolutions.append(quote! {
+                let offset = match base.checked_add(#arg as u64) {
+                    Some(offset) => offset,
+                    None => {
+                        let msg = format!("Offset is out of range (base: {}, offset: {})", base, #arg);
+                        return Err(Error::new(ErrorKind::InvalidData, msg));
+                    }
+                };
+                rdr.seek(SeekFrom::Start(offset))?;
+                let #arg = <#target as structure::format::Format>::decode::<__E, _>(rdr)?;
+            });
+        }
+    }
+    if resolutions.as_str().is_empty() {
+        return Tokens::new();
+    }
+    let types: Vec<Ident> = types.into_iter().map(Ident::from).collect();
+    let types_with = types.clone();
+    quote! {
+        #[allow(unused)]
+        fn unpack_from_seek<__R: Read + Seek>(&self, rdr: &mut __R, base: u64) -> Result<(#(#types,)*)> {
+            self.unpack_from_seek_with::<#endianness, _>(rdr, base)
+        }
+
+        #[allow(unused)]
+        fn unpack_from_seek_with<__E: ByteOrder, __R: Read + Seek>(&self, rdr: &mut __R, base: u64)
+            -> Result<(#(#types_with,)*)> {
+            let (#args) = self.unpack_from_with::<__E, _>(rdr)?;
+            let position = rdr.stream_position()?;
+            #resolutions
+            rdr.seek(SeekFrom::Start(position))?;
+            Ok((#args))
+        }
+    }
+}
+
+/// Write a single value of a scalar field (anything that is not a buffer) from `arg`
+fn write_scalar(value: &StructValue, arg: &Ident, endianness: &Tokens) -> Tokens {
+    match *value.kind() {
+        ValueKind::Number => match value.enum_type {
+            Some(_) => {
+                let integer_type = Ident::from(value.type_name().as_str());
+                write_number(value.type_name(), &quote!(Into::<#integer_type>::into(#arg)), endianness)
+            }
+            None => write_number(value.type_name(), &quote!(#arg), endianness),
+        },
+        ValueKind::HalfFloat => quote! {
+            wtr.write_u16::<#endianness>(structure::half::f32_to_f16(#arg))?;
+        },
+        ValueKind::UserType => match value.length_prefix {
+            Some(prefix_type) => {
+                // Encode the record first, for its length
+                let prefix_type_ident = Ident::from(prefix_type);
+                let writing = write_number(prefix_type, &quote!(record.len() as #prefix_type_ident), endianness);
+                quote! {
+                    let mut record = vec![];
+                    structure::format::Format::encode::<#endianness, _>(#arg, &mut record)?;
+                    if record.len() as u64 > #prefix_type_ident::MAX as u64 {
+                        let msg = format!("Record is too long for its length prefix \
+                            (prefix type: {}, actual size: {})", #prefix_type, record.len());
+                        return Err(Error::new(ErrorKind::InvalidInput, msg));
+                    }
+                    #writing
+                    wtr.write_all(&record)?;
+                }
+            }
+            None => quote! {
+                structure::format::Format::encode::<#endianness, _>(#arg, wtr)?;
+            },
+        },
+        ValueKind::Boolean => quote! {
+            let buf = if #arg { TRUE_BUF } else { FALSE_BUF };
+            wtr.write_all(buf)?;
+        },
+        ValueKind::Char => quote! {
+            if !#arg.is_ascii() {
+                let msg = format!("Character is not ASCII (character: {:?})", #arg);
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+            wtr.write_u8(#arg as u8)?;
+        },
+        // The size of the target, which the proc macro doesn't know
+        ValueKind::NativeSize => match value.type_name().as_str() {
+            "isize" => quote! {
+                wtr.write_int::<#endianness>(#arg as i64, ::std::mem::size_of::<isize>())?;
+            },
+            _ => quote! {
+                wtr.write_uint::<#endianness>(#arg as u64, ::std::mem::size_of::<usize>())?;
+            },
+        },
+        ValueKind::Pointer => {
+            let size = mem::size_of::<usize>();
+            let integer_type = Ident::from(format!("u{}", size * 8));
+            let byteorder_fn = Ident::from(format!("write_u{}", size * 8));
+            quote! {
+                let v = #arg as #integer_type;
+                wtr.#byteorder_fn::<#endianness>(v)?;
+            }
+        }
+        ValueKind::Varint(encoding) => {
+            let varint_fn = Ident::from(format!("write_{}", encoding));
+            quote! {
+                structure::varint::#varint_fn(wtr, #arg)?;
+            }
+        }
+        ValueKind::SizedInteger(bytes) => {
+            let (signed, wide_type, byteorder_fn) = sized_integer_fn(value, "write");
+            let bits = bytes * 8;
+            let range_check = if signed {
+                let limit = 1u128 << (bits - 1);
+                let min = Ident::from(format!("-{}{}", limit, value.type_name()));
+                let max = Ident::from(format!("{}{}", limit - 1, value.type_name()));
+                quote! { !(#min..=#max).contains(&#arg) }
+            } else {
+                let max = Ident::from(format!("{}{}", (1u128 << bits) - 1, value.type_name()));
+                quote! { #arg > #max }
+            };
+            quote! {
+                if #range_check {
+                    let msg = format!("Value does not fit in a {}-bit integer (value: {})", #bits, #arg);
+                    return Err(Error::new(ErrorKind::InvalidInput, msg));
+                }
+                wtr.#byteorder_fn::<#endianness>(#arg as #wide_type, #bytes)?;
+            }
+        }
+        _ => panic!("'{}' is not a scalar type", value.type_name()),
+    }
+}
+
+/// Build an expression that reads a single value of a scalar field (anything that is not a buffer)
+fn read_scalar(value: &StructValue, endianness: &Tokens) -> Tokens {
+    match *value.kind() {
+        ValueKind::Number => read_number(value.type_name(), endianness),
+        ValueKind::HalfFloat => quote! {
+            structure::half::f16_to_f32(rdr.read_u16::<#endianness>()?)
+        },
+        ValueKind::UserType => {
+            let user_type = Ident::from(value.type_name().as_str());
+            let prefix_type = match value.length_prefix {
+                Some(prefix_type) => prefix_type,
+                None => return quote! {
+                    <#user_type as structure::format::Format>::decode::<#endianness, _>(rdr)?
+                },
+            };
+            // The record may not read past its length, and the bytes it doesn't read are skipped
+            let reading = read_number(prefix_type, endianness);
+            quote! {{
+                let length = #reading as u64;
+                let mut region = rdr.by_ref().take(length);
+                let record = match <#user_type as structure::format::Format>::decode::<#endianness, _>(&mut region) {
+                    Err(ref err) if err.kind() == ErrorKind::UnexpectedEof && region.limit() == 0 => {
+                        let msg = format!("Record is longer than its length prefix (length: {})", length);
+                        return Err(Error::new(ErrorKind::InvalidData, msg));
+                    }
+                    result => result?,
+                };
+                let leftover = region.limit();
+                if std::io::copy(&mut region, &mut std::io::sink())? < leftover {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+                }
+                record
+            }}
+        }
+        ValueKind::Boolean => quote! {
+            rdr.read_u8()? != 0 // 0 is false
+        },
+        ValueKind::Char => quote! {{
+            let c = rdr.read_u8()?;
+            if !c.is_ascii() {
+                let msg = format!("Character is not ASCII (byte: {:#x})", c);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            c as char
+        }},
+        ValueKind::NativeSize => match value.type_name().as_str() {
+            "isize" => quote! {
+                rdr.read_int::<#endianness>(::std::mem::size_of::<isize>())? as isize
+            },
+            _ => quote! {
+                rdr.read_uint::<#endianness>(::std::mem::size_of::<usize>())? as usize
+            },
+        },
+        ValueKind::Pointer => {
+            let pointer_type = Ident::from(value.type_name().as_str());
+            let size = mem::size_of::<usize>();
+            let byteorder_fn = Ident::from(format!("read_u{}", size * 8));
+            quote! {
+                rdr.#byteorder_fn::<#endianness>()? as #pointer_type
+            }
+        }
+        ValueKind::Varint(encoding) => {
+            let varint_fn = Ident::from(format!("read_{}", encoding));
+            quote! {
+                structure::varint::#varint_fn(rdr)?
+            }
+        }
+        ValueKind::SizedInteger(bytes) => {
+            let (_, _, byteorder_fn) = sized_integer_fn(value, "read");
+            let integer_type = Ident::from(value.type_name().as_str());
+            // byteorder sign-extends signed integers
+            quote! {
+                rdr.#byteorder_fn::<#endianness>(#bytes)? as #integer_type
+            }
+        }
+        _ => panic!("'{}' is not a scalar type", value.type_name()),
+    }
+}
+
+/// Write a number of a primitive type, like `u16`
+fn write_number(type_name: &str, value: &Tokens, endianness: &Tokens) -> Tokens {
+    let byteorder_fn = Ident::from(format!("write_{}", type_name));
+    match type_name {
+        "u8" | "i8" => quote! { wtr.#byteorder_fn(#value)?; },
+        _ => quote! { wtr.#byteorder_fn::<#endianness>(#value)?; },
+    }
+}
+
+/// Build an expression that reads a number of a primitive type, like `u16`
+fn read_number(type_name: &str, endianness: &Tokens) -> Tokens {
+    let byteorder_fn = Ident::from(format!("read_{}", type_name));
+    match type_name {
+        "u8" | "i8" => quote! { rdr.#byteorder_fn()? },
+        _ => quote! { rdr.#byteorder_fn::<#endianness>()? },
+    }
+}
+
+/// Return whether a sized integer is signed, the type byteorder works with for it, and the
+/// byteorder function (`read` or `write`) that handles it
+fn sized_integer_fn(value: &StructValue, direction: &str) -> (bool, Ident, Ident) {
+    let signed = value.type_name().starts_with('i');
+    let (wide_type, fn_suffix) = match (signed, value.type_name() == "u128" || value.type_name() == "i128") {
+        (false, false) => ("u64", "uint"),
+        (true, false) => ("i64", "int"),
+        (false, true) => ("u128", "uint128"),
+        (true, true) => ("i128", "int128"),
+    };
+    (signed, Ident::from(wide_type), Ident::from(format!("{}_{}", direction, fn_suffix)))
+}
+
+/// Write a field that holds the count of other fields, from the length of their arguments
+fn build_count_writing(values: &[StructValue], value: &StructValue, endianness: &Tokens) -> Tokens {
+    let arg_indices = arg_indices(values);
+    let mut lengths = value.counts.iter().map(|&i| {
+        let arg = Ident::from(format!("_{}", arg_indices[i]));
+        // An absent conditional field is empty
+        if values[i].condition.is_some() {
+            quote!(#arg.map_or(0, |arg| arg.len()))
+        } else {
+            quote!(#arg.len())
+        }
+    });
+    let first = lengths.next().unwrap();
+    let mut tokens = quote! {
+        let count = #first;
+    };
+    for other in lengths {
+        tokens.append(quote! {
+            if #other != count {
+                let msg = format!("Fields that share a count have different lengths ({} and {})", count, #other);
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+        });
+    }
+    let count_type = Ident::from(value.type_name().as_str());
+    let bits = match *value.kind() {
+        ValueKind::SizedInteger(bytes) => bytes * 8,
+        ValueKind::Varint(_) => 64,
+        _ => type_size(value.type_name()) * 8,
+    };
+    if bits < 64 {
+        let max = (1u64 << bits) - 1;
+        tokens.append(quote! {
+            if count as u64 > #max {
+                let msg = format!("Length does not fit in its count field (maximum: {}, length: {})", #max, count);
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+        });
+    }
+    let writing = write_scalar(value, &Ident::from("count"), endianness);
+    tokens.append(quote! {
+        let count = count as #count_type;
+        #writing
+    });
+    tokens
+}
+
+/// Write a field whose count (or byte length) is held by another field
+fn build_counted_writing(value: &StructValue, count: &CountSpec, arg: &Ident, endianness: &Tokens) -> Tokens {
+    let mut tokens = Tokens::new();
+    if let Some(limit) = count.limit {
+        tokens.append(quote! {
+            if #arg.len() as u64 > #limit {
+                let msg = format!("Length exceeds the limit of its count (limit: {}, length: {})", #limit, #arg.len());
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+        });
+    }
+    if value.is_buffer() {
+        tokens.append(quote! { wtr.write_all(#arg)?; });
+    } else if value.kind == ValueKind::UserType {
+        let writing = write_scalar(value, &Ident::from("item"), endianness);
+        tokens.append(quote! {
+            for item in #arg {
+                #writing
+            }
+        });
+    } else {
+        let writing = write_scalar(value, &Ident::from("item"), endianness);
+        tokens.append(quote! {
+            for &item in #arg {
+                #writing
+            }
+        });
+    }
+    tokens
+}
+
+/// Read a field whose count (or byte length) is held by another field
+fn build_counted_reading(values: &[StructValue], value: &StructValue, count: &CountSpec, arg: &Ident,
+                         endianness: &Tokens) -> Tokens {
+    let count_index = values.iter().position(|v| v.name.as_ref() == Some(&count.field)).unwrap();
+    let count_value = Ident::from(format!("count_{}", count_index));
+    let limit = match count.limit {
+        Some(limit) => quote!(#limit),
+        None => quote!(usize::MAX as u64),
+    };
+    let mut tokens = quote! {
+        if #count_value as u64 > #limit {
+            let msg = format!("Count exceeds its limit (limit: {}, count: {})", #limit, #count_value);
+            return Err(Error::new(ErrorKind::InvalidData, msg));
+        }
+        let count = #count_value as usize;
+    };
+    if value.is_buffer() {
+        // Don't trust the count for allocating the buffer up front
+        tokens.append(quote! {
+            let mut #arg = vec![];
+            rdr.by_ref().take(count as u64).read_to_end(&mut #arg)?;
+            if #arg.len() < count {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "failed to fill whole buffer"));
+            }
+        });
+    } else {
+        let reading = read_scalar(value, endianness);
+        tokens.append(quote! {
+            let mut #arg = Vec::with_capacity(std::cmp::min(count, 1024));
+            for _ in 0..count {
+                #arg.push(#reading);
+            }
+        });
+    }
+    tokens
+}
+
+/// Convert an integer that is read into an enum-typed field
+fn build_enum_conversion(value: &StructValue, enum_type: &str, arg_index: usize, reading: &Tokens) -> Tokens {
+    let arg = Ident::from(format!("_{}", arg_index));
+    let integer_type = Ident::from(value.type_name().as_str());
+    let enum_type_ident = Ident::from(enum_type);
+    // The index in the unpacked values starts from 0
+    let index = arg_index - 1;
+    quote! {
+        let offset = rdr.position();
+        let raw = #reading;
+        let #arg = match <#enum_type_ident as TryFrom<#integer_type>>::try_from(raw) {
+            Ok(value) => value,
+            Err(_) => {
+                return Err(Error::new(ErrorKind::InvalidData, structure::error::InvalidDiscriminant {
+                    offset,
+                    index: #index,
+                    type_name: #enum_type,
+                    value: i128::from(raw),
+                }));
+            }
+        };
+    }
+}
+
+/// Return the shift of each bitfield in its integer
+fn bitfield_shifts(value: &StructValue, bitfields: &Bitfields) -> Vec<usize> {
+    let total_width = integer_width(value);
+    let mut offset = 0;
+    bitfields.fields.iter().map(|field| {
+        offset += field.width;
+        if bitfields.lsb_first { offset - field.width } else { total_width - offset }
+    }).collect()
+}
+
+/// Return the width in bits of an integer field
+fn integer_width(value: &StructValue) -> usize {
+    match *value.kind() {
+        ValueKind::SizedInteger(bytes) => bytes * 8,
+        _ => type_size(value.type_name()) * 8,
+    }
+}
+
+/// Write an integer that is made of bitfields, from an argument for each named bitfield
+fn build_bitfields_writing(value: &StructValue, bitfields: &Bitfields, arg_index: &mut usize,
+                           endianness: &Tokens) -> Tokens {
+    let integer_type = Ident::from(value.type_name().as_str());
+    let mut tokens = quote! {
+        let mut bits: #integer_type = 0;
+    };
+    for (field, shift) in bitfields.fields.iter().zip(bitfield_shifts(value, bitfields)) {
+        let name = match field.name {
+            Some(ref name) => name,
+            None => continue,
+        };
+        *arg_index += 1;
+        let current_arg = Ident::from(format!("_{}", arg_index));
+        if field.width > 1 && field.width < type_size(field.type_name()) * 8 {
+            let width = field.width;
+            let max = Ident::from(format!("{}{}", (1u128 << width) - 1, field.type_name()));
+            tokens.append(quote! {
+                if #current_arg > #max {
+                    let msg = format!("Bitfield {} does not fit in {} bits (value: {})", #name, #width, #current_arg);
+                    return Err(Error::new(ErrorKind::InvalidInput, msg));
+                }
+            });
+        }
+        let shifted = match shift {
+            0 => quote!(#current_arg as #integer_type),
+            _ => quote!((#current_arg as #integer_type) << #shift),
+        };
+        tokens.append(quote! {
+            bits |= #shifted;
+        });
+    }
+    tokens.append(write_scalar(value, &Ident::from("bits"), endianness));
+    tokens
+}
+
+/// Read an integer that is made of bitfields, into a variable for each named bitfield
+fn build_bitfields_reading(value: &StructValue, bitfields: &Bitfields, arg_index: &mut usize,
+                           endianness: &Tokens) -> Tokens {
+    let reading = read_scalar(value, endianness);
+    let mut tokens = quote! {
+        let bits = #reading;
+    };
+    for (field, shift) in bitfields.fields.iter().zip(bitfield_shifts(value, bitfields)) {
+        if field.name.is_none() {
+            continue;
+        }
+        *arg_index += 1;
+        let current_arg = Ident::from(format!("_{}", arg_index));
+        let shifted = match shift {
+            0 => quote!(bits),
+            _ => quote!((bits >> #shift)),
+        };
+        let masked = if field.width == integer_width(value) {
+            shifted
+        } else {
+            let mask = Ident::from(format!("{:#x}", (1u128 << field.width) - 1));
+            quote!((#shifted & #mask))
+        };
+        let field_type = Ident::from(field.type_name());
+        let bitfield = match field.width {
+            1 => quote!(#masked != 0),
+            _ => quote!(#masked as #field_type),
+        };
+        tokens.append(quote! {
+            let #current_arg = #bitfield;
+        });
+    }
+    tokens
+}
+
+/// Build the sentinel of a sentinel-terminated array, as a `Vec<u8>`
+fn build_sentinel(array: &TerminatedArray, endianness: &Tokens) -> Tokens {
+    let entry_size = calc_size(&array.group);
+    let writing = build_literal_writing(&array.sentinel, endianness);
+    quote! {
+        let sentinel = {
+            let mut sentinel = Vec::with_capacity(#entry_size);
+            let wtr = &mut sentinel;
+            #writing
+            sentinel
+        };
+    }
+}
+
+/// Write the entries of a sentinel-terminated array, followed by its sentinel
+fn build_terminated_writing(array: &TerminatedArray, arg: &Ident, endianness: &Tokens) -> Tokens {
+    let entry_size = calc_size(&array.group);
+    let entry_args: Vec<Ident> = (1..=arg_types(&array.group).len()).map(|i| Ident::from(format!("_{}", i))).collect();
+    // The entries are borrowed, and enums may not be `Copy`
+    let mut bindings = Tokens::new();
+    for (entry_arg, is_enum) in entry_args.iter().zip(values_enum_args(&array.group)) {
+        if is_enum {
+            bindings.append(quote!(let #entry_arg = #entry_arg.clone();));
+        } else {
+            bindings.append(quote!(let #entry_arg = *#entry_arg;));
+        }
+    }
+    let pattern = if entry_args.len() == 1 {
+        quote!(#(#entry_args)*)
+    } else {
+        quote!((#(#entry_args,)*))
+    };
+    let writings = build_writings(&array.group, endianness);
+    let sentinel = build_sentinel(array, endianness);
+    let mut tokens = Tokens::new();
+    if let Some(limit) = array.limit {
+        tokens.append(quote! {
+            if #arg.len() as u64 > #limit {
+                let msg = format!("Array has more entries than its maximum (maximum: {}, entries: {})", #limit, #arg.len());
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+        });
+    }
+    tokens.append(quote! {
+        #sentinel
+        for #pattern in #arg {
+            #bindings
+            let mut entry = Vec::with_capacity(#entry_size);
+            {
+                let wtr = &mut entry;
+                #writings
+            }
+            if entry == sentinel {
+                let msg = "Array entry is the same as the sentinel that ends the array".to_owned();
+                return Err(Error::new(ErrorKind::InvalidInput, msg));
+            }
+            wtr.write_all(&entry)?;
+        }
+        wtr.write_all(&sentinel)?;
+    });
+    tokens
+}
+
+/// Read the entries of a sentinel-terminated array, up to its sentinel
+fn build_terminated_reading(array: &TerminatedArray, arg: &Ident, endianness: &Tokens) -> Tokens {
+    let entry_size = calc_size(&array.group);
+    let entry_args: Vec<Ident> = (1..=arg_types(&array.group).len()).map(|i| Ident::from(format!("_{}", i))).collect();
+    let entry = if entry_args.len() == 1 {
+        quote!(#(#entry_args)*)
+    } else {
+        quote!((#(#entry_args,)*))
+    };
+    let readings = build_readings(&array.group, endianness);
+    let sentinel = build_sentinel(array, endianness);
+    let limit_check = match array.limit {
+        Some(limit) => quote! {
+            if entries.len() as u64 == #limit {
+                let msg = format!("Array has more entries than its maximum (maximum: {})", #limit);
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+        },
+        None => Tokens::new(),
+    };
+    quote! {
+        #sentinel
+        // The entries are read into variables that may hide the array's variable
+        let mut entries = vec![];
+        loop {
+            let mut entry = [0u8; #entry_size];
+            rdr.read_exact(&mut entry)?;
+            if entry[..] == sentinel[..] {
+                break;
+            }
+            #limit_check
+            let rdr = &mut Cursor::new(&entry[..]);
+            #readings
+            entries.push(#entry);
+        }
+        let #arg = entries;
+    }
+}
+
+/// Write a literal field
+fn build_literal_writing(value: &StructValue, endianness: &Tokens) -> Tokens {
+    if let ValueKind::ByteLiteral(ref bytes) = *value.kind() {
+        let bytes = Ident::from(format!("{:?}", bytes));
+        return quote! { wtr.write_all(&#bytes)?; };
+    }
+    let literal = value.literal.as_ref().unwrap().to_tokens(value.type_name());
+    let writing = match *value.kind() {
+        ValueKind::SizedInteger(bytes) => {
+            let (_, wide_type, byteorder_fn) = sized_integer_fn(value, "write");
+            let literal = value.literal.as_ref().unwrap().to_tokens(wide_type.as_ref());
+            quote! { wtr.#byteorder_fn::<#endianness>(#literal, #bytes)?; }
+        }
+        _ => write_number(value.type_name(), &quote!(#literal), endianness),
+    };
+    let mut tokens = Tokens::new();
+    for _ in 0..value.repeat() {
+        tokens.append(writing.clone());
+    }
+    tokens
+}
+
+/// Read a literal field, and check that it has the value from the format
+fn build_literal_check(value: &StructValue, endianness: &Tokens) -> Tokens {
+    if let ValueKind::ByteLiteral(ref bytes) = *value.kind() {
+        let length = bytes.len();
+        let bytes = Ident::from(format!("{:?}", bytes));
+        return quote! {
+            let offset = rdr.position();
+            let mut actual = [0u8; #length];
+            rdr.read_exact(&mut actual)?;
+            if actual != #bytes {
+                return Err(Error::new(ErrorKind::InvalidData, structure::error::LiteralMismatch {
+                    offset,
+                    expected: structure::error::Literal::Bytes(#bytes.to_vec()),
+                    actual: structure::error::Literal::Bytes(actual.to_vec()),
+                }));
+            }
+        };
+    }
+    let integer_type = Ident::from(value.type_name().as_str());
+    let literal = value.literal.as_ref().unwrap().to_tokens(value.type_name());
+    let reading = match *value.kind() {
+        ValueKind::SizedInteger(bytes) => {
+            let (_, _, byteorder_fn) = sized_integer_fn(value, "read");
+            quote! { rdr.#byteorder_fn::<#endianness>(#bytes)? as #integer_type }
+        }
+        _ => read_number(value.type_name(), endianness),
+    };
+    let (variant, wide_type) = if value.type_name().starts_with('i') {
+        (Ident::from("Signed"), Ident::from("i128"))
+    } else {
+        (Ident::from("Unsigned"), Ident::from("u128"))
+    };
+    let check = quote! {
+        let offset = rdr.position();
+        let actual: #integer_type = #reading;
+        if actual != #literal {
+            return Err(Error::new(ErrorKind::InvalidData, structure::error::LiteralMismatch {
+                offset,
+                expected: structure::error::Literal::#variant(#wide_type::from(#literal)),
+                actual: structure::error::Literal::#variant(#wide_type::from(actual)),
+            }));
+        }
+    };
+    let mut tokens = Tokens::new();
+    for _ in 0..value.repeat() {
+        tokens.append(check.clone());
+    }
+    tokens
+}
+
+/// Return the index of the first argument of each field (the indices start from 1)
+fn arg_indices(values: &[StructValue]) -> Vec<usize> {
+    let mut arg_index = 1;
+    values.iter().map(|v| {
+        let index = arg_index;
+        arg_index += v.arg_count();
+        index
+    }).collect()
+}
+
+/// Build the args list, the function declaration args list and the type list (each item is followed
+/// by a comma, so the lists are also tuples)
+fn build_args_list(values: &[StructValue]) -> (Tokens, Tokens, Tokens) {
+    let mut args = vec![];
+    let mut fn_decl_args = vec![];
+    let mut args_types = vec![];
+    for (i, (decl_type, result_type)) in arg_types(values).into_iter().enumerate() {
+        args.push(Ident::from(format!("_{}", i + 1)));
+        fn_decl_args.push(Ident::from(format!("_{}: {}", i + 1, decl_type)));
+        args_types.push(Ident::from(result_type));
+    }
+    (quote!(#(#args,)*), quote!(#(#fn_decl_args,)*), quote!(#(#args_types,)*))
+}
+
+/// Return the type of each argument when packing, and when unpacking
+fn arg_types(values: &[StructValue]) -> Vec<(String, String)> {
+    let mut types = vec![];
+    for v in values {
+        if let Some(ref bitfields) = v.bitfields {
+            for field in bitfields.fields.iter().filter(|field| field.name.is_some()) {
+                types.push((field.type_name().to_owned(), field.type_name().to_owned()));
+            }
+            continue;
+        }
+        let (decl_type, result_type) = if let Some(ref array) = v.array {
+            // An array of entries, which are tuples if they have more than one field
+            let types = arg_types(&array.group);
+            if types.len() == 1 {
+                (format!("&[{}]", types[0].0), format!("Vec<{}>", types[0].1))
+            } else {
+                let decl_types: Vec<&str> = types.iter().map(|t| t.0.as_str()).collect();
+                let result_types: Vec<&str> = types.iter().map(|t| t.1.as_str()).collect();
+                (format!("&[({})]", decl_types.join(", ")), format!("Vec<({})>", result_types.join(", ")))
+            }
+        } else if v.count.is_some() && !v.is_buffer() {
+            // An array
+            (format!("&[{}]", v.type_name()), format!("Vec<{}>", v.type_name()))
+        } else if v.is_buffer() {
+            (v.type_name().clone(), "Vec<u8>".to_owned())
+        } else if v.kind == ValueKind::UserType {
+            (format!("&{}", v.type_name()), v.type_name().clone())
+        } else if let Some(ref enum_type) = v.enum_type {
+            (enum_type.clone(), enum_type.clone())
+        } else {
+            (v.type_name().clone(), v.type_name().clone())
+        };
+        let (decl_type, result_type) = if v.condition.is_some() {
+            (format!("Option<{}>", decl_type), format!("Option<{}>", result_type))
+        } else {
+            (decl_type, result_type)
+        };
+        for _ in 0..v.arg_count() {
+            types.push((decl_type.clone(), result_type.clone()));
+        }
+    }
+    types
+}
+
+/// Build the expression of a size that `calc_size` (or `calc_max_size`) returns, with the sizes that
+/// it leaves out since only the compiler knows them
+fn build_size(size: usize, values: &[StructValue], max: bool) -> Tokens {
+    let mut terms = vec![];
+    for v in values.iter().filter(|v| v.is_compiler_sized()) {
+        if max && v.length_prefix.is_some() {
+            // Included in the maximum length of the record
+            continue;
+        }
+        let number = match v.count {
+            Some(ref count) if max => count.limit.unwrap() as usize,
+            Some(_) => continue,
+            None if v.condition.is_some() && !max => continue,
+            None => v.repeat(),
+        };
+        let element_size = match v.kind {
+            ValueKind::NativeSize => quote!(::std::mem::size_of::<usize>()),
+            _ => {
+                let user_type = Ident::from(v.type_name().as_str());
+                quote!(<#user_type as structure::format::Format>::SIZE)
+            }
         };
-        readings.append(reading);
+        terms.push(match number {
+            1 => element_size,
+            _ => quote!(#element_size * #number),
+        });
+    }
+    if size != 0 || terms.is_empty() {
+        terms.insert(0, quote!(#size));
+    }
+    let mut tokens = terms.remove(0);
+    for term in terms {
+        tokens.append(quote!(+ #term));
     }
+    tokens
+}
 
+fn build_size_fn(size: &Tokens, max_size: Option<Tokens>) -> Tokens {
+    let max_size = match max_size {
+        Some(max_size) => quote!(Some(#max_size)),
+        None => quote!(None),
+    };
     quote! {
         #[allow(unused)]
-        fn unpack_from<T: Read>(&self, rdr: &mut T) -> Result<(#args_types,)> {
-            #readings
-            Ok((#args,))
+        fn size(&self) -> usize {
+            #size
+        }
+
+        #[allow(unused)]
+        fn size_hint(&self) -> (usize, Option<usize>) {
+            (#size, #max_size)
         }
     }
 }
 
-/// Build the args list, the function declaration args list and the type list
-fn build_args_list(values: &[StructValue]) -> (Tokens, Tokens, Tokens) {
-    let mut args = vec![];
-    let mut fn_decl_args = vec![];
-    let mut args_types = vec![];
-    let mut arg_index = 0;
+/// Return whether the packed size depends on the values (then `calc_size` is the minimum size)
+fn is_variable_size(values: &[StructValue]) -> bool {
+    values.iter().any(|v| {
+        v.count.is_some() || v.condition.is_some() ||
+            matches!(*v.kind(), ValueKind::CString | ValueKind::PrefixedBuffer(_) | ValueKind::Varint(_) |
+                     ValueKind::Rest | ValueKind::Terminated | ValueKind::Align(_)) || v.length_prefix.is_some()
+    })
+}
+
+/// Return the maximum packed size, or `None` if it is unbounded
+fn calc_max_size(values: &[StructValue]) -> Option<usize> {
+    let mut size: usize = 0;
     for v in values {
-        match *v.kind() {
-            ValueKind::Padding => continue,
-            ValueKind::Buffer | ValueKind::FixedBuffer => {
-                arg_index += 1;
-                args.push(Ident::from(format!("_{}", arg_index)));
-                fn_decl_args.push(Ident::from(format!("_{}: {}", arg_index, v.type_name())));
-                args_types.push(Ident::from("Vec<u8>".to_owned()));
-            }
-            _ => {
-                for _ in 0..v.repeat() {
-                    arg_index += 1;
-                    args.push(Ident::from(format!("_{}", arg_index)));
-                    fn_decl_args.push(Ident::from(format!("_{}: {}", arg_index, v.type_name())));
-                    args_types.push(Ident::from(v.type_name().as_str()));
-                }
-            }
-        }
+        let max_size = match v.count {
+            Some(ref count) => (count.limit? as usize).checked_mul(element_max_size(v)?)?,
+            None => element_max_size(v)?.checked_mul(v.repeat())?,
+        };
+        size = size.checked_add(max_size)?;
     }
-    (quote!(#(#args),*), quote!(#(#fn_decl_args),*), quote!(#(#args_types),*))
+    Some(size)
 }
 
-fn build_size_fn(size: usize) -> Tokens {
-    quote! {
-        #[allow(unused)]
-        fn size(&self) -> usize {
-            #size
+/// Return the maximum size of a single element of a field, or `None` if it is unbounded
+fn element_max_size(v: &StructValue) -> Option<usize> {
+    match *v.kind() {
+        ValueKind::CString | ValueKind::Rest => None,
+        ValueKind::Align(alignment) => Some(alignment - 1),
+        ValueKind::Terminated => {
+            // The entries and the sentinel
+            let array = v.array.as_ref().unwrap();
+            calc_size(&array.group).checked_mul((array.limit? as usize).checked_add(1)?)
         }
+        ValueKind::Varint("zigzag32") => Some(MAX_VARINT32_LENGTH),
+        ValueKind::Varint(_) => Some(MAX_VARINT_LENGTH),
+        ValueKind::PrefixedBuffer(prefix_type) => prefixed_max_size(prefix_type),
+        ValueKind::UserType => v.length_prefix.map_or(Some(0), prefixed_max_size),
+        _ => Some(element_size(v)),
     }
 }
 
+/// Return the maximum size of a length prefix of the given type and the bytes after it
+fn prefixed_max_size(prefix_type: &str) -> Option<usize> {
+    let max_length = 1usize.checked_shl(8 * type_size(prefix_type) as u32).map(|n| n - 1);
+    type_size(prefix_type).checked_add(max_length?)
+}
+
 fn calc_size(values: &[StructValue]) -> usize {
     let mut size = 0;
     for v in values {
-        if v.type_name().starts_with("*") {
-            mem::size_of::<*const c_void>();
-        }
-        let type_size = match v.type_name().as_str() {
-            "i8" => mem::size_of::<i8>(),
-            "&[u8]" | "u8" => mem::size_of::<u8>(),
-            "bool" => 1,
-            "i16" => mem::size_of::<i16>(),
-            "u16" => mem::size_of::<u16>(),
-            "i32" => mem::size_of::<i32>(),
-            "u32" => mem::size_of::<u32>(),
-            "i64" => mem::size_of::<i64>(),
-            "u64" => mem::size_of::<u64>(),
-            "f32" => mem::size_of::<f32>(),
-            "f64" => mem::size_of::<f64>(),
-            t if t.starts_with("*") => mem::size_of::<usize>(),
-            _ => panic!("Unknown type: '{}'", v.type_name()),
-        };
-        size += type_size * v.repeat();
+        if v.count.is_some() || v.condition.is_some() {
+            // The minimum, since the count may be zero and the field may be absent
+            continue;
+        }
+        size += element_size(v) * v.repeat();
     }
     size
 }
 
+/// Return the (minimum) size of a single element of a field
+fn element_size(v: &StructValue) -> usize {
+    match *v.kind() {
+        ValueKind::HalfFloat => 2,
+        // The minimum, since a varint takes at least one byte
+        ValueKind::Varint(_) => 1,
+        ValueKind::SizedInteger(bytes) => bytes,
+        ValueKind::PrefixedBuffer(prefix_type) => type_size(prefix_type),
+        ValueKind::ByteLiteral(_) => 1,
+        // The minimum, since there may be nothing left
+        ValueKind::Rest => 0,
+        // The minimum, which is only the sentinel
+        ValueKind::Terminated => calc_size(&v.array.as_ref().unwrap().group),
+        // Added by `build_size` (the length prefix is the minimum of a length-prefixed record)
+        ValueKind::UserType => v.length_prefix.map_or(0, type_size),
+        ValueKind::NativeSize => 0,
+        // The minimum, since the offset may already be aligned
+        ValueKind::Align(_) => 0,
+        _ => type_size(v.type_name()),
+    }
+}
+
+fn type_size(type_name: &str) -> usize {
+    match type_name {
+        "i8" => mem::size_of::<i8>(),
+        "&[u8]" | "u8" => mem::size_of::<u8>(),
+        "bool" | "char" => 1,
+        "i16" => mem::size_of::<i16>(),
+        "u16" => mem::size_of::<u16>(),
+        "i32" => mem::size_of::<i32>(),
+        "u32" => mem::size_of::<u32>(),
+        "i64" => mem::size_of::<i64>(),
+        "u64" => mem::size_of::<u64>(),
+        "i128" => mem::size_of::<i128>(),
+        "u128" => mem::size_of::<u128>(),
+        "f32" => mem::size_of::<f32>(),
+        "f64" => mem::size_of::<f64>(),
+        t if t.starts_with("*") => mem::size_of::<usize>(),
+        _ => panic!("Unknown type: '{}'", type_name),
+    }
+}
+
 fn format_to_struct_name(format: &str) -> String {
-    format!("Struct_{}", format.replace("?", "Bool")
+    let name = format.replace("?", "Bool")
         .replace("=", "Native")
         .replace("<", "LittleEndian")
         .replace(">", "")
-        .replace("!", ""))
+        .replace("!", "");
+    // Any other character that is not allowed in identifiers (like in literals) is replaced
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!("Struct_{}", name)
 }
 
 /// Return the format string without the endianness, and the endianness
 fn format_endianness(format: &str) -> (&str, Endianness) {
-    let first_char = format.chars().nth(0);
+    let first_char = format.chars().next();
     let endianness = match first_char {
         Some('=') => Endianness::Native,
         Some('<') => Endianness::LittleEndian,
@@ -344,11 +2292,25 @@ fn char_to_type(c: char) -> (&'static str, ValueKind) {
         'I' => ("u32", ValueKind::Number),
         'q' => ("i64", ValueKind::Number),
         'Q' => ("u64", ValueKind::Number),
+        'o' => ("i128", ValueKind::Number),
+        'O' => ("u128", ValueKind::Number),
+        'v' => ("u64", ValueKind::Varint("uleb128")),
+        'V' => ("i64", ValueKind::Varint("sleb128")),
+        'w' => ("u64", ValueKind::Varint("vlq")),
+        'k' => ("i32", ValueKind::Varint("zigzag32")),
+        'K' => ("i64", ValueKind::Varint("zigzag64")),
         'f' => ("f32", ValueKind::Number),
         'd' => ("f64", ValueKind::Number),
+        'e' => ("f32", ValueKind::HalfFloat),
         's' => ("&[u8]", ValueKind::Buffer),
         'S' => ("&[u8]", ValueKind::FixedBuffer),
-        'P' => ("*const c_void", ValueKind::Pointer),
+        'p' => ("&[u8]", ValueKind::PascalString),
+        'z' => ("&[u8]", ValueKind::CString),
+        '*' => ("&[u8]", ValueKind::Rest),
+        'n' => ("isize", ValueKind::NativeSize),
+        'N' => ("usize", ValueKind::NativeSize),
+        'c' => ("char", ValueKind::Char),
+        'P' => ("*const ::std::os::raw::c_void", ValueKind::Pointer),
         'x' => ("u8", ValueKind::Padding),
         _ => panic!("Unknown format: '{}'", c),
     }
@@ -356,73 +2318,883 @@ fn char_to_type(c: char) -> (&'static str, ValueKind) {
 
 fn format_to_values(format: &str) -> (Vec<StructValue>, Endianness) {
     let (format, endianness) = format_endianness(format);
+    let mut values = parse_values(format, endianness);
+    resolve_alignments(&mut values);
+    (values, endianness)
+}
+
+/// Replace each alignment whose offset is known in advance with the padding that it takes
+fn resolve_alignments(values: &mut [StructValue]) {
+    let mut offset = Some(0);
+    for value in values.iter_mut() {
+        if let ValueKind::Align(alignment) = value.kind {
+            if let Some(current) = offset {
+                let padding = (alignment - current % alignment) % alignment;
+                *value = StructValue::new("u8".to_owned(), padding, ValueKind::Padding);
+                offset = Some(current + padding);
+            }
+            continue;
+        }
+        if value.is_compiler_sized() || is_variable_size(std::slice::from_ref(value)) {
+            offset = None;
+        }
+        offset = offset.map(|current| current + element_size(value) * value.repeat());
+    }
+}
+
+/// Parse the fields of a format (without its endianness)
+fn parse_values(format: &str, endianness: Endianness) -> Vec<StructValue> {
     let mut values = vec![];
     let mut chars = format.chars().peekable();
     let mut repeat_str = String::new();
+    let mut count = None;
+    let mut condition = None;
     while let Some(c) = chars.next() {
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             repeat_str.push(c);
+        } else if c.is_whitespace() {
+            // Whitespace between format characters is ignored
+            if !repeat_str.is_empty() {
+                panic!("The number {} must be followed by a format character", repeat_str);
+            }
+            if count.is_some() || condition.is_some() {
+                panic!("A count or a condition must be followed by a format character");
+            }
+        } else if c == '(' {
+            if !repeat_str.is_empty() || count.is_some() {
+                panic!("A field cannot have both a count and a count field");
+            }
+            count = Some(parse_count(&mut chars));
+        } else if c == '[' {
+            if !repeat_str.is_empty() {
+                panic!("A condition must come before the count of its field");
+            }
+            if condition.is_some() {
+                panic!("A field cannot have more than one condition");
+            }
+            condition = Some(parse_condition(&mut chars));
+        } else if c == '~' {
+            if !repeat_str.is_empty() || count.is_some() {
+                panic!("A sentinel-terminated array cannot have a count");
+            }
+            let mut value = parse_terminated_array(&mut chars, endianness);
+            value.condition = condition.take();
+            values.push(value);
+        } else if c == '{' {
+            let type_name = take_word(&mut chars, ':');
+            if type_name.is_empty() || chars.next() != Some('}') {
+                panic!("An opening brace must be followed by the name of a user-defined type and a closing brace");
+            }
+            if count.is_some() && !repeat_str.is_empty() {
+                panic!("A field cannot have both a count and a count field");
+            }
+            let length_prefix = if chars.peek() == Some(&'<') {
+                if count.is_some() || !repeat_str.is_empty() {
+                    panic!("A length-prefixed record cannot have a count");
+                }
+                Some(parse_length_prefix(&mut chars))
+            } else {
+                None
+            };
+            if chars.peek() == Some(&':') {
+                panic!("A field of a user-defined type cannot be named");
+            }
+            let repeat = if repeat_str.is_empty() { 1 } else { repeat_str.parse().expect("not a number") };
+            repeat_str.clear();
+            let mut value = StructValue::new(type_name, repeat, ValueKind::UserType);
+            value.count = count.take();
+            value.condition = condition.take();
+            value.length_prefix = length_prefix;
+            values.push(value);
+        } else if c == '#' {
+            if !repeat_str.is_empty() {
+                panic!("A checksum cannot have a count");
+            }
+            if count.is_some() {
+                panic!("Only numbers and buffers can have a count field");
+            }
+            if condition.is_some() {
+                panic!("Only numbers and buffers can be conditional");
+            }
+            let parameter = parse_type_parameter(&mut chars, "Checksum")
+                .expect("'#' must be followed by a checksum algorithm, like in \"#<crc32>\"");
+            let (type_name, checksum) = parse_checksum(&parameter);
+            values.push(StructValue::new(type_name.to_owned(), 1, ValueKind::Checksum(checksum)));
+        } else if c == '\'' {
+            if !repeat_str.is_empty() {
+                panic!("A byte string literal cannot have a count");
+            }
+            if count.is_some() {
+                panic!("Only numbers and buffers can have a count field");
+            }
+            if condition.is_some() {
+                panic!("Only numbers and buffers can be conditional");
+            }
+            let bytes = parse_byte_string_literal(&mut chars);
+            values.push(StructValue::new("&[u8]".to_owned(), bytes.len(), ValueKind::ByteLiteral(bytes)));
         } else {
-            let (type_name, kind) = char_to_type(c);
-            let mut type_name = type_name.to_owned();
+            let mut enum_type = None;
+            let (mut type_name, mut kind) = if c == 'u' || (c == 'i' && chars.peek() == Some(&'<')) {
+                // Parse integer width in bits, or the enum type of 'i'
+                match parse_type_parameter(&mut chars, "Integer width") {
+                    Some(parameter) => {
+                        if c == 'i' && !parameter.starts_with(|c: char| c.is_ascii_digit()) {
+                            enum_type = Some(parameter);
+                            ("i32".to_owned(), ValueKind::Number)
+                        } else {
+                            sized_integer_type(c == 'i', parameter.parse().expect("Integer width must be a number"))
+                        }
+                    }
+                    None => panic!("'u' must be followed by a width in bits, like in \"u<24>\""),
+                }
+            } else {
+                let (type_name, kind) = char_to_type(c);
+                (type_name.to_owned(), kind)
+            };
+            if matches!(c, 'b' | 'B' | 'h' | 'H' | 'I' | 'q' | 'Q') {
+                enum_type = parse_type_parameter(&mut chars, "Enum type");
+            }
+            if kind == ValueKind::NativeSize && endianness != Endianness::Native {
+                panic!("'n' and 'N' can be used only if the endianness is native. \
+                        To change the endianness to native, start the format with '='");
+            }
             if kind == ValueKind::Pointer {
                 // Parse pointer type
                 if endianness != Endianness::Native {
                     panic!("Pointer can be used only if the endianness is native. \
                             To change the endianness to native, start the format with '='");
                 }
-                if let Some(&'<') = chars.peek() {
-                    chars.next();
-                    let mut pointer_type_name = String::new();
-                    loop {
-                        let c = chars.next();
-                        if c == None {
-                            panic!("Pointer type must end with '>'");
-                        } else if c == Some('>') {
-                            if pointer_type_name.is_empty() {
-                                panic!("Pointer type cannot be empty");
-                            }
-                            type_name = format!("*const {}", pointer_type_name);
-                            break;
-                        } else {
-                            pointer_type_name.push(c.unwrap());
-                        }
-                    }
+                if let Some(pointer_type_name) = parse_type_parameter(&mut chars, "Pointer type") {
+                    type_name = format!("*const {}", pointer_type_name);
+                }
+            }
+            if c == 'x' && chars.peek() == Some(&'<') {
+                // Parse the alignment
+                let parameter = parse_type_parameter(&mut chars, "Alignment").unwrap();
+                let alignment = parameter.parse().ok().filter(|&alignment| alignment > 0)
+                    .unwrap_or_else(|| panic!("Alignment must be a positive number (got '{}')", parameter));
+                if !repeat_str.is_empty() || condition.is_some() {
+                    panic!("An alignment cannot have a count or a condition");
+                }
+                kind = ValueKind::Align(alignment);
+            }
+            if c == 's' && chars.peek() == Some(&'<') {
+                let prefix_type = parse_length_prefix(&mut chars);
+                if !repeat_str.is_empty() {
+                    panic!("A length-prefixed buffer cannot have a count");
                 }
+                kind = ValueKind::PrefixedBuffer(prefix_type);
+            }
+            if kind == ValueKind::Rest && !repeat_str.is_empty() {
+                panic!("'*' takes the rest of the bytes, so it cannot have a count");
+            }
+            if kind == ValueKind::CString && !repeat_str.is_empty() {
+                kind = ValueKind::FixedCString;
+            }
+            if count.is_some() && !repeat_str.is_empty() {
+                panic!("A field cannot have both a count and a count field");
             }
             let mut repeat = 1;
             if !repeat_str.is_empty() {
                 repeat = repeat_str.parse().expect("not a number");
                 repeat_str.clear();
             }
-            values.push(StructValue::new(type_name, repeat, kind));
+            let mut value = StructValue::new(type_name, repeat, kind);
+            value.enum_type = enum_type;
+            if let Some(count) = count.take() {
+                if value.enum_type.is_some() {
+                    panic!("An enum-typed field cannot have a count field");
+                }
+                if !value.is_scalar() && !matches!(value.kind, ValueKind::Buffer | ValueKind::FixedBuffer) {
+                    panic!("Only numbers and buffers can have a count field");
+                }
+                value.count = Some(count);
+            }
+            value.condition = condition.take();
+            if chars.peek() == Some(&'@') {
+                chars.next();
+                if chars.next() != Some('{') {
+                    panic!("'@' must be followed by the name of a user-defined type in braces");
+                }
+                let target = take_word(&mut chars, ':');
+                if target.is_empty() || chars.next() != Some('}') {
+                    panic!("An opening brace must be followed by the name of a user-defined type and a closing brace");
+                }
+                if !value.is_unsigned_integer() || value.count.is_some() || value.condition.is_some() {
+                    panic!("An offset must be a single unsigned integer");
+                }
+                value.target = Some(target);
+            }
+            // Braces right after an integer hold its bitfields, unless they hold a type name like in "I{Rec}"
+            if chars.peek() == Some(&'{') && !braces_hold_type_name(&chars) {
+                chars.next();
+                let bitfields = parse_bitfields(&mut chars, &value);
+                for name in bitfields.fields.iter().filter_map(|field| field.name.as_ref()) {
+                    if has_name(&values, name) || bitfields.fields.iter().filter(|f| f.name.as_ref() == Some(name)).count() > 1 {
+                        panic!("Field name '{}' is used more than once", name);
+                    }
+                }
+                value.bitfields = Some(bitfields);
+            }
+            if chars.peek() == Some(&'=') {
+                chars.next();
+                if value.bitfields.is_some() || value.enum_type.is_some() {
+                    panic!("An integer with bitfields or an enum type cannot be a literal");
+                }
+                let literal = take_word(&mut chars, '-');
+                value.literal = Some(parse_integer_literal(&literal, &value));
+            }
+            if chars.peek() == Some(&':') {
+                chars.next();
+                let name = take_word(&mut chars, '_');
+                if name.is_empty() {
+                    panic!("':' must be followed by a field name");
+                }
+                if has_name(&values, &name) {
+                    panic!("Field name '{}' is used more than once", name);
+                }
+                value.name = Some(name);
+            }
+            values.push(value);
         }
     }
     if !repeat_str.is_empty() {
         panic!("No format character is followed by the number {}", repeat_str);
     }
-    (values, endianness)
+    if count.is_some() {
+        panic!("No format character follows the last count");
+    }
+    if condition.is_some() {
+        panic!("No format character follows the last condition");
+    }
+    if values.iter().rev().skip(1).any(|v| v.kind == ValueKind::Rest) {
+        panic!("'*' takes the rest of the bytes, so it must be the last format character");
+    }
+    resolve_counts(&mut values);
+    resolve_conditions(&values);
+    values
+}
+
+/// Parse the type of a length prefix like "<H>", and return its Rust type
+fn parse_length_prefix<I: Iterator<Item = char>>(chars: &mut Peekable<I>) -> &'static str {
+    let prefix = parse_type_parameter(chars, "Length prefix type").unwrap();
+    match prefix.as_str() {
+        "B" | "H" | "I" | "Q" | "O" => char_to_type(prefix.chars().next().unwrap()).0,
+        _ => panic!("Length prefix type must be one of 'B', 'H', 'I', 'Q' or 'O' (got '{}')", prefix),
+    }
+}
+
+/// Take the alphanumeric characters, underscores and `extra` characters that come next
+fn take_word<I: Iterator<Item = char>>(chars: &mut Peekable<I>, extra: char) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if !c.is_ascii_alphanumeric() && c != '_' && c != extra {
+            break;
+        }
+        word.push(c);
+        chars.next();
+    }
+    word
+}
+
+/// Return whether the braces that come next hold the name of a user-defined type rather than bitfields
+fn braces_hold_type_name<I: Iterator<Item = char> + Clone>(chars: &Peekable<I>) -> bool {
+    let mut chars = chars.clone();
+    if chars.next() != Some('{') || !chars.peek().is_some_and(|&c| c.is_ascii_alphabetic() || c == '_') {
+        return false;
+    }
+    let name = take_word(&mut chars, ':');
+    chars.next() == Some('}') && name != "lsb" && name != "msb"
+}
+
+/// Parse the rest of a sentinel-terminated array like "~(2I)" or "~(H)=0xFFFF<=64", after its '~'
+fn parse_terminated_array<I: Iterator<Item = char>>(chars: &mut Peekable<I>, endianness: Endianness) -> StructValue {
+    if chars.next() != Some('(') {
+        panic!("'~' must be followed by the fields of an entry in parentheses, like in \"~(2I)\"");
+    }
+    let mut group_format = String::new();
+    let mut depth = 1;
+    loop {
+        let c = chars.next().expect("The fields of an entry must end with ')'");
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            _ => {}
+        }
+        group_format.push(c);
+    }
+    let group = parse_values(&group_format, endianness);
+    if group.iter().any(|v| v.is_compiler_sized() || matches!(v.kind, ValueKind::Align(_))) {
+        panic!("The entries of a sentinel-terminated array cannot have user-defined types, 'n', 'N' or \
+                alignments");
+    }
+    if arg_types(&group).is_empty() || is_variable_size(&group) ||
+        group.iter().any(|v| v.is_implicit() || v.name.is_some()) {
+        panic!("The entries of a sentinel-terminated array must have a fixed size, and fields that are \
+                packed from arguments");
+    }
+    let entry_size = calc_size(&group);
+
+    let sentinel = if chars.peek() == Some(&'=') {
+        chars.next();
+        if chars.peek() == Some(&'\'') {
+            chars.next();
+            let bytes = parse_byte_string_literal(chars);
+            if bytes.len() != entry_size {
+                panic!("The sentinel must have the size of an entry ({} bytes)", entry_size);
+            }
+            StructValue::new("&[u8]".to_owned(), bytes.len(), ValueKind::ByteLiteral(bytes))
+        } else {
+            let kind = match group[0].kind {
+                ValueKind::Number => ValueKind::Number,
+                ValueKind::SizedInteger(bytes) => ValueKind::SizedInteger(bytes),
+                _ => ValueKind::Padding,
+            };
+            if group.len() != 1 || group[0].repeat != 1 || kind == ValueKind::Padding {
+                panic!("An integer sentinel needs entries of a single integer, like in \"~(H)=0xFFFF\"");
+            }
+            let mut sentinel = StructValue::new(group[0].type_name.clone(), 1, kind);
+            sentinel.literal = Some(parse_integer_literal(&take_word(chars, '-'), &sentinel));
+            sentinel
+        }
+    } else {
+        // An all-zero entry by default
+        StructValue::new("&[u8]".to_owned(), entry_size, ValueKind::ByteLiteral(vec![0; entry_size]))
+    };
+
+    let mut limit = None;
+    if chars.peek() == Some(&'<') {
+        chars.next();
+        if chars.next() != Some('=') {
+            panic!("The maximum number of entries must follow \"<=\", like in \"~(2I)<=64\"");
+        }
+        let max = take_word(chars, '_');
+        limit = Some(max.replace('_', "").parse().unwrap_or_else(|_| panic!("Invalid maximum number of entries: '{}'", max)));
+    }
+
+    let mut value = StructValue::new("&[u8]".to_owned(), 1, ValueKind::Terminated);
+    value.array = Some(Box::new(TerminatedArray { group, sentinel, limit }));
+    value
+}
+
+/// Parse the rest of the bitfields of an integer like "{4:version 4:ihl 8:tos}", after its opening brace
+fn parse_bitfields<I: Iterator<Item = char>>(chars: &mut Peekable<I>, value: &StructValue) -> Bitfields {
+    if !value.is_unsigned_integer() || matches!(value.kind, ValueKind::Varint(_)) {
+        panic!("Only a single unsigned integer of a fixed size can have bitfields");
+    }
+    if value.count.is_some() || value.condition.is_some() {
+        panic!("An integer with bitfields cannot have a count field or be conditional");
+    }
+    let mut spec = String::new();
+    loop {
+        match chars.next() {
+            None => panic!("Bitfields must end with a closing brace"),
+            Some('}') => break,
+            Some(c) => spec.push(c),
+        }
+    }
+    let mut words = spec.split_whitespace().peekable();
+    // The bit order is MSB-first by default
+    let lsb_first = words.peek() == Some(&"lsb");
+    if matches!(words.peek(), Some(&"lsb") | Some(&"msb")) {
+        words.next();
+    }
+    let mut fields = vec![];
+    for word in words {
+        let (width, name) = match word.find(':') {
+            Some(i) => (&word[..i], Some(word[i + 1..].to_owned())),
+            None => (word, None),
+        };
+        let width: usize = width.parse().unwrap_or_else(|_| panic!("Invalid bitfield: '{}'", word));
+        if width == 0 || name.as_ref().is_some_and(|name| name.is_empty() ||
+                                                   !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+            panic!("Invalid bitfield: '{}'", word);
+        }
+        fields.push(Bitfield { width, name });
+    }
+    let total_width: usize = fields.iter().map(|field| field.width).sum();
+    if total_width != integer_width(value) {
+        panic!("Bitfields must add up to the width of their integer ({} bits, got {})", integer_width(value), total_width);
+    }
+    Bitfields { lsb_first, fields }
+}
+
+/// Return whether a field or a bitfield has the given name
+fn has_name(values: &[StructValue], name: &str) -> bool {
+    values.iter().any(|v| {
+        v.name.as_deref() == Some(name) ||
+            v.bitfields.as_ref().is_some_and(|b| b.fields.iter().any(|field| field.name.as_deref() == Some(name)))
+    })
+}
+
+/// Find the field (or bitfield) that a condition tests, and return its argument index and a field of its type
+fn find_condition_field(values: &[StructValue], name: &str) -> (usize, StructValue) {
+    let arg_indices = arg_indices(values);
+    for (i, v) in values.iter().enumerate() {
+        if v.name.as_deref() == Some(name) {
+            if v.is_implicit() || v.condition.is_some() {
+                panic!("Condition field '{}' must be packed from an argument, and cannot be conditional", name);
+            }
+            return (arg_indices[i], StructValue::new(v.type_name.clone(), v.repeat, v.kind.clone()));
+        }
+        if let Some(ref bitfields) = v.bitfields {
+            let named_fields = bitfields.fields.iter().filter(|field| field.name.is_some());
+            for (offset, field) in named_fields.enumerate() {
+                if field.name.as_deref() == Some(name) {
+                    let kind = if field.width == 1 { ValueKind::Boolean } else { ValueKind::Number };
+                    return (arg_indices[i] + offset, StructValue::new(field.type_name().to_owned(), 1, kind));
+                }
+            }
+        }
+    }
+    panic!("Condition field '{}' must be defined before the fields that use it", name);
+}
+
+/// Parse the rest of a count like "(count)" or "(count<=100)", after its opening parenthesis
+fn parse_count<I: Iterator<Item = char>>(chars: &mut Peekable<I>) -> CountSpec {
+    let mut spec = String::new();
+    loop {
+        match chars.next() {
+            None => panic!("Count must end with ')'"),
+            Some(')') => break,
+            Some(c) => spec.push(c),
+        }
+    }
+    let (field, limit) = match spec.find("<=") {
+        Some(i) => {
+            let limit = spec[i + 2..].trim().parse().unwrap_or_else(|_| panic!("Invalid count limit: '{}'", spec));
+            (spec[..i].trim().to_owned(), Some(limit))
+        }
+        None => (spec.trim().to_owned(), None),
+    };
+    if field.is_empty() {
+        panic!("Count must name a field, like in \"H:count (count)I\"");
+    }
+    CountSpec { field, limit }
+}
+
+/// Link each field that has a count field to the field it refers to
+fn resolve_counts(values: &mut [StructValue]) {
+    for i in 0..values.len() {
+        let field = match values[i].count {
+            Some(ref count) => count.field.clone(),
+            None => continue,
+        };
+        let j = match values[..i].iter().position(|v| v.name.as_ref() == Some(&field)) {
+            Some(j) => j,
+            None => panic!("Count field '{}' must be defined before the fields that use it", field),
+        };
+        let count_value = &mut values[j];
+        if !count_value.is_unsigned_integer() || count_value.type_name == "u128" || count_value.literal.is_some() ||
+            count_value.condition.is_some() {
+            panic!("Count field '{}' must be a single unsigned integer of at most 64 bits", field);
+        }
+        count_value.counts.push(i);
+    }
+}
+
+/// Parse the rest of a condition like "[flags]" or "[flags&0x80]", after its opening bracket
+fn parse_condition<I: Iterator<Item = char>>(chars: &mut Peekable<I>) -> Condition {
+    let mut spec = String::new();
+    loop {
+        match chars.next() {
+            None => panic!("Condition must end with ']'"),
+            Some(']') => break,
+            Some(c) => spec.push(c),
+        }
+    }
+    let (field, mask) = match spec.find('&') {
+        Some(i) => (spec[..i].trim().to_owned(), Some(spec[i + 1..].trim().to_owned())),
+        None => (spec.trim().to_owned(), None),
+    };
+    if field.is_empty() {
+        panic!("Condition must name a field, like in \"B:flags [flags&0x80]H\"");
+    }
+    Condition { field, mask }
+}
+
+/// Check that each conditional field can be optional, and that the field it tests can be tested
+fn resolve_conditions(values: &[StructValue]) {
+    for (i, value) in values.iter().enumerate() {
+        let condition = match value.condition {
+            Some(ref condition) => condition,
+            None => continue,
+        };
+        if value.is_implicit() || value.kind == ValueKind::Padding {
+            panic!("Only numbers and buffers can be conditional");
+        }
+        if value.is_scalar() && value.count.is_none() && value.repeat != 1 || value.bitfields.is_some() {
+            panic!("A conditional number cannot have a repeat count");
+        }
+        let (_, flag) = find_condition_field(&values[..i], &condition.field);
+        match condition.mask {
+            Some(ref mask) => {
+                if !flag.is_unsigned_integer() {
+                    panic!("Condition field '{}' must be a single unsigned integer to test its bits", condition.field);
+                }
+                parse_integer_literal(mask, &flag);
+            }
+            None => {
+                let is_bool = flag.kind == ValueKind::Boolean && flag.repeat == 1;
+                if !flag.is_unsigned_integer() && !is_bool {
+                    panic!("Condition field '{}' must be a single unsigned integer or bool", condition.field);
+                }
+            }
+        }
+    }
+}
+
+/// Return the expression that tests whether a conditional field is present
+fn condition_test(values: &[StructValue], condition: &Condition) -> Tokens {
+    let (flag_arg, flag_value) = find_condition_field(values, &condition.field);
+    let flag = Ident::from(format!("_{}", flag_arg));
+    match condition.mask {
+        Some(ref mask) => {
+            let mask = parse_integer_literal(mask, &flag_value).to_tokens(flag_value.type_name());
+            quote!(#flag & #mask != 0)
+        }
+        None if flag_value.kind == ValueKind::Boolean => quote!(#flag),
+        None => quote!(#flag != 0),
+    }
+}
+
+/// Parse the rest of a byte string literal like '\x7fELF', after its opening quote
+fn parse_byte_string_literal<I: Iterator<Item = char>>(chars: &mut Peekable<I>) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        match chars.next() {
+            None => panic!("Byte string literal must end with '"),
+            Some('\'') => break,
+            Some('\\') => {
+                let escaped = match chars.next() {
+                    Some('x') => {
+                        let hex: String = chars.by_ref().take(2).collect();
+                        u8::from_str_radix(&hex, 16).unwrap_or_else(|_| panic!("Invalid escape sequence: \\x{}", hex))
+                    }
+                    Some('0') => 0,
+                    Some('n') => b'\n',
+                    Some('r') => b'\r',
+                    Some('t') => b'\t',
+                    Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => c as u8,
+                    c => panic!("Invalid escape sequence in byte string literal: {:?}", c),
+                };
+                bytes.push(escaped);
+            }
+            Some(c) => {
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    if bytes.is_empty() {
+        panic!("Byte string literal cannot be empty");
+    }
+    bytes
+}
+
+/// Parse the integer literal of a field like "I=0xCAFEBABE", and check that it fits in the field
+fn parse_integer_literal(literal: &str, value: &StructValue) -> IntegerLiteral {
+    let bits = match *value.kind() {
+        ValueKind::SizedInteger(bytes) => bytes * 8,
+        ValueKind::Number if !value.type_name().starts_with('f') => type_size(value.type_name()) * 8,
+        _ => panic!("Only integer fields can have a literal value (got '{}')", value.type_name()),
+    };
+    let signed = value.type_name().starts_with('i');
+    let (negative, digits) = match literal.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, literal),
+    };
+    let (radix, digits) = match digits.get(..2) {
+        Some("0x") | Some("0X") => (16, &digits[2..]),
+        Some("0o") => (8, &digits[2..]),
+        Some("0b") => (2, &digits[2..]),
+        _ => (10, digits),
+    };
+    let magnitude = u128::from_str_radix(&digits.replace('_', ""), radix)
+        .unwrap_or_else(|_| panic!("Invalid integer literal: '{}'", literal));
+    let max = if signed {
+        let limit = 1u128 << (bits - 1);
+        if negative { limit } else { limit - 1 }
+    } else if negative && magnitude != 0 {
+        panic!("Literal {} is negative but its field is unsigned", literal);
+    } else {
+        u128::MAX >> (128 - bits)
+    };
+    if magnitude > max {
+        panic!("Literal {} does not fit in its field ({}-bit {})", literal, bits,
+               if signed { "signed integer" } else { "unsigned integer" });
+    }
+    IntegerLiteral { negative, magnitude }
+}
+
+/// Parse an optional `<...>` parameter that follows a format character
+fn parse_type_parameter<I: Iterator<Item = char>>(chars: &mut Peekable<I>, name: &str) -> Option<String> {
+    if chars.peek() != Some(&'<') {
+        return None;
+    }
+    chars.next();
+    let mut parameter = String::new();
+    loop {
+        match chars.next() {
+            None => panic!("{} must end with '>'", name),
+            Some('>') => {
+                if parameter.is_empty() {
+                    panic!("{} cannot be empty", name);
+                }
+                return Some(parameter);
+            }
+            Some(c) => parameter.push(c),
+        }
+    }
+}
+
+/// Return the smallest Rust integer that holds an integer of `bits` bits
+fn sized_integer_type(signed: bool, bits: usize) -> (String, ValueKind) {
+    if bits == 0 || !bits.is_multiple_of(8) || bits > 128 {
+        panic!("Integer width must be a multiple of 8 between 8 and 128 bits (got {})", bits);
+    }
+    let bytes = bits / 8;
+    let type_bits = bytes.next_power_of_two() * 8;
+    let type_name = format!("{}{}", if signed { "i" } else { "u" }, type_bits);
+    if type_bits == bits {
+        (type_name, ValueKind::Number)
+    } else {
+        (type_name, ValueKind::SizedInteger(bytes))
+    }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 enum ValueKind {
     Number,
+    /// An integer that takes the given number of bytes, which is narrower than its Rust type
+    SizedInteger(usize),
+    /// A variable-length integer, in the given encoding of `structure::varint`
+    Varint(&'static str),
+    HalfFloat,
+    /// `isize` or `usize`, packed with the target's pointer width
+    NativeSize,
     Boolean,
+    /// An ASCII character that takes one byte
+    Char,
     Buffer,
     FixedBuffer,
+    /// A length prefix of the given type followed by that many bytes
+    PrefixedBuffer(&'static str),
+    /// A length byte followed by the data, in a field with a fixed size
+    PascalString,
+    /// A NUL-terminated string of any length
+    CString,
+    /// A NUL-padded string in a field with a fixed size
+    FixedCString,
+    /// All the bytes that are left, at the end of the format
+    Rest,
+    /// Entries of the given fields, up to a sentinel entry
+    Terminated,
+    /// A type that implements `structure::format::Format`, whose size is only known to the compiler
+    UserType,
+    /// Bytes that are fixed by the format
+    ByteLiteral(Vec<u8>),
+    /// A checksum over other bytes of the record
+    Checksum(ChecksumSpec),
     Pointer,
     Padding,
+    /// Zeros up to the next multiple of the given number of bytes, after fields whose size is not
+    /// known in advance (other alignments become `Padding`)
+    Align(usize),
+}
+
+#[derive(Clone, PartialEq)]
+struct ChecksumSpec {
+    /// The name of the function in `structure::checksum`
+    algorithm: &'static str,
+    /// The offset of the first covered byte
+    start: usize,
+    end: RangeEnd,
+}
+
+/// Where the bytes that a checksum covers end
+#[derive(Clone, PartialEq)]
+enum RangeEnd {
+    /// Right before the checksum field
+    Field,
+    /// At the end of the record
+    Record,
+    Offset(usize),
+}
+
+/// Parse the parameter of a checksum field, like "crc32 4" in "#<crc32 4>"
+fn parse_checksum(parameter: &str) -> (&'static str, ChecksumSpec) {
+    let mut words = parameter.split_whitespace();
+    let (algorithm, type_name) = match words.next() {
+        Some("crc32") => ("crc32", "u32"),
+        Some("crc16") => ("crc16", "u16"),
+        Some("internet") => ("internet", "u16"),
+        Some("xor8") => ("xor8", "u8"),
+        _ => panic!("Checksum must be one of 'crc32', 'crc16', 'internet' or 'xor8' (got '{}')", parameter),
+    };
+    let parse_offset = |offset: &str| -> usize {
+        offset.parse().unwrap_or_else(|_| panic!("Invalid checksum range: '{}'", parameter))
+    };
+    let (start, end) = match words.next() {
+        None => (0, RangeEnd::Field),
+        Some(range) => match range.find("..") {
+            None => (parse_offset(range), RangeEnd::Field),
+            Some(i) if i + 2 == range.len() => (parse_offset(&range[..i]), RangeEnd::Record),
+            Some(i) => (parse_offset(&range[..i]), RangeEnd::Offset(parse_offset(&range[i + 2..]))),
+        },
+    };
+    if words.next().is_some() {
+        panic!("Invalid checksum: '{}'", parameter);
+    }
+    (type_name, ChecksumSpec { algorithm, start, end })
+}
+
+/// The value of an integer field that is fixed by the format
+struct IntegerLiteral {
+    negative: bool,
+    magnitude: u128,
+}
+
+impl IntegerLiteral {
+    /// Return the literal as Rust code of the given integer type
+    fn to_tokens(&self, type_name: &str) -> Ident {
+        Ident::from(format!("{}{}{}", if self.negative { "-" } else { "" }, self.magnitude, type_name))
+    }
+}
+
+/// A reference from a field to an earlier field that holds its count (or byte length, for buffers)
+struct CountSpec {
+    /// The name of the count field
+    field: String,
+    /// The maximum count
+    limit: Option<u64>,
+}
+
+/// A test of an earlier field that decides whether a field is present
+struct Condition {
+    /// The name of the tested field
+    field: String,
+    /// The bits to test (the field is tested for being nonzero, or `true`, if there is no mask)
+    mask: Option<String>,
+}
+
+/// The bitfields of an integer field, like in "H{4:version 4:ihl 8:tos}"
+struct Bitfields {
+    /// Whether the first bitfield is the least significant bits (rather than the most significant)
+    lsb_first: bool,
+    fields: Vec<Bitfield>,
+}
+
+struct Bitfield {
+    width: usize,
+    /// The name of the bitfield, or `None` for reserved bits (which are zeros)
+    name: Option<String>,
+}
+
+impl Bitfield {
+    /// Return the narrowest type that holds the bitfield
+    fn type_name(&self) -> &'static str {
+        match self.width {
+            1 => "bool",
+            2..=8 => "u8",
+            9..=16 => "u16",
+            17..=32 => "u32",
+            33..=64 => "u64",
+            _ => "u128",
+        }
+    }
+}
+
+/// An array of entries that ends with a sentinel entry
+struct TerminatedArray {
+    /// The fields of each entry
+    group: Vec<StructValue>,
+    /// The entry that ends the array, as a literal field
+    sentinel: StructValue,
+    /// The maximum number of entries
+    limit: Option<u64>,
 }
 
 struct StructValue {
     type_name: String,
     repeat: usize,
-    kind: ValueKind
+    kind: ValueKind,
+    literal: Option<IntegerLiteral>,
+    /// The name that other fields use to refer to this field
+    name: Option<String>,
+    /// The field that holds the count of this field
+    count: Option<CountSpec>,
+    /// The indices of the fields whose count this field holds
+    counts: Vec<usize>,
+    /// The test that decides whether the field is present
+    condition: Option<Condition>,
+    /// The entries and the sentinel of a sentinel-terminated array
+    array: Option<Box<TerminatedArray>>,
+    /// The bitfields that an integer is split into
+    bitfields: Option<Bitfields>,
+    /// The enum that an integer is converted to and from, like "Status" in "B<Status>"
+    enum_type: Option<String>,
+    /// The user-defined type at the offset that an integer holds, like "Header" in "I@{Header}"
+    target: Option<String>,
+    /// The type of the byte length that prefixes a user-defined type, like "u16" in "{Record}<H>"
+    length_prefix: Option<&'static str>,
 }
 
 impl StructValue {
     fn new(type_name: String, repeat: usize, kind: ValueKind) -> StructValue {
-        StructValue { type_name: type_name, repeat: repeat, kind: kind }
+        StructValue { type_name, repeat, kind, literal: None, name: None, count: None, counts: vec![],
+                      condition: None, array: None, bitfields: None, enum_type: None, target: None,
+                      length_prefix: None }
+    }
+    /// Return whether the field is determined by the format or by other fields, and so it is not
+    /// packed from or unpacked to an argument
+    fn is_implicit(&self) -> bool {
+        self.literal.is_some() || !self.counts.is_empty() ||
+            matches!(self.kind, ValueKind::ByteLiteral(_) | ValueKind::Checksum(_))
+    }
+    /// Return the number of arguments the field is packed from (and unpacked to)
+    fn arg_count(&self) -> usize {
+        if self.is_implicit() || matches!(self.kind, ValueKind::Padding | ValueKind::Align(_)) {
+            0
+        } else if let Some(ref bitfields) = self.bitfields {
+            bitfields.fields.iter().filter(|field| field.name.is_some()).count()
+        } else if self.count.is_some() || self.is_buffer() || self.array.is_some() {
+            1
+        } else {
+            self.repeat
+        }
+    }
+    /// Return whether each of the field's values is a single number (or a `bool`, `char`, pointer or
+    /// value of a user-defined type)
+    fn is_scalar(&self) -> bool {
+        matches!(self.kind, ValueKind::Number | ValueKind::Boolean | ValueKind::Pointer | ValueKind::HalfFloat |
+                 ValueKind::NativeSize | ValueKind::Char | ValueKind::Varint(_) | ValueKind::SizedInteger(_) |
+                 ValueKind::UserType)
+    }
+    /// Return whether the size of the field is only known to the compiler (for the target), and so
+    /// it is added by `build_size`
+    fn is_compiler_sized(&self) -> bool {
+        matches!(self.kind, ValueKind::UserType | ValueKind::NativeSize)
+    }
+    /// Return whether the field is a single unsigned integer
+    fn is_unsigned_integer(&self) -> bool {
+        let is_unsigned = match self.kind {
+            ValueKind::Number => matches!(self.type_name.as_str(), "u8" | "u16" | "u32" | "u64" | "u128"),
+            ValueKind::SizedInteger(_) => self.type_name.starts_with('u'),
+            ValueKind::Varint(encoding) => encoding == "uleb128" || encoding == "vlq",
+            _ => false,
+        };
+        is_unsigned && self.repeat == 1 && self.enum_type.is_none()
+    }
+    /// Return whether the field is a buffer, whose repeat count is its length
+    fn is_buffer(&self) -> bool {
+        matches!(self.kind, ValueKind::Buffer | ValueKind::FixedBuffer | ValueKind::PascalString |
+                 ValueKind::PrefixedBuffer(_) | ValueKind::CString | ValueKind::FixedCString | ValueKind::Rest)
     }
     fn type_name(&self) -> &String {
         &self.type_name
@@ -437,7 +3209,7 @@ impl StructValue {
 
 fn trim_quotes(input: &str) -> &str {
 
-    if input.chars().nth(0) != Some('"') && input.chars().last() != Some('"') || input.len() < 2 {
+    if !input.starts_with('"') && !input.ends_with('"') || input.len() < 2 {
         panic!("structure!() macro takes a literal string as an argument");
     }
     &input[1..(input.len()-1)]
@@ -5,6 +5,110 @@ use std::os::raw::c_void;
 use std::mem::transmute;
 use std::io::ErrorKind;
 use std::io::Cursor;
+use structure::byteorder::{ByteOrder, BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+enum Status {
+    Idle = 1,
+    Busy = 2,
+    Failed = 0xff,
+}
+
+impl std::convert::TryFrom<u8> for Status {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Status, u8> {
+        match value {
+            1 => Ok(Status::Idle),
+            2 => Ok(Status::Busy),
+            0xff => Ok(Status::Failed),
+            _ => Err(value),
+        }
+    }
+}
+
+impl From<Status> for u8 {
+    fn from(status: Status) -> u8 {
+        status as u8
+    }
+}
+
+/// An enum that is not `Copy`
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Plain,
+    Framed,
+}
+
+impl std::convert::TryFrom<u8> for Kind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Kind, u8> {
+        match value {
+            0 => Ok(Kind::Plain),
+            1 => Ok(Kind::Framed),
+            _ => Err(value),
+        }
+    }
+}
+
+impl From<Kind> for u8 {
+    fn from(kind: Kind) -> u8 {
+        match kind {
+            Kind::Plain => 0,
+            Kind::Framed => 1,
+        }
+    }
+}
+
+/// A fixed-point angle in 1/100 degrees
+#[derive(Debug, Clone, PartialEq)]
+struct Angle(i32);
+
+impl structure::format::Format for Angle {
+    const SIZE: usize = 3;
+
+    fn encode<E: ByteOrder, W: std::io::Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+        wtr.write_i24::<E>(self.0)
+    }
+
+    fn decode<E: ByteOrder, R: std::io::Read>(rdr: &mut R) -> std::io::Result<Angle> {
+        rdr.read_i24::<E>().map(Angle)
+    }
+}
+
+structure_enum! {
+    #[derive(Debug, PartialEq)]
+    enum Message: "<B" {
+        Ping = 1 => "I",
+        Data = 0x02 => "H:len (len)s ?",
+        Big = 3 => ">I",
+        Quit = 4 => "",
+        Turn = 6 => "{Angle}",
+        Frame = 7 => "?:flag [flag]B<Kind>",
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Header: "<H B 's'";
+
+    #[derive(Debug, PartialEq)]
+    struct Framing: "2B<Kind> H";
+
+    #[derive(Debug, PartialEq)]
+    struct Pair: "H B";
+}
+
+// A module has a single `structure_enum!`, so more layouts are declared in another one
+mod records {
+    structure_enum! {
+        #[derive(Debug, PartialEq)]
+        pub struct Point: "<2h";
+
+        #[derive(Debug, PartialEq)]
+        pub struct Segment: "<{Point} {Point}";
+    }
+}
 
 
 #[test]
@@ -21,7 +125,7 @@ fn pack_into() {
 
 #[test]
 fn unpack() {
-    assert_eq!(structure!("I").unpack(&[0, 0, 0, 3]).unwrap(), (3, ));
+    assert_eq!(structure!("I").unpack([0, 0, 0, 3]).unwrap(), (3, ));
 }
 
 #[test]
@@ -36,7 +140,7 @@ fn pack_2_values() {
 
 #[test]
 fn unpack_2_values() {
-    assert_eq!(structure!("If").unpack(&[0, 0, 0, 6, 64, 166, 102, 102]).unwrap(), (6, 5.2));
+    assert_eq!(structure!("If").unpack([0, 0, 0, 6, 64, 166, 102, 102]).unwrap(), (6, 5.2));
 }
 
 #[test]
@@ -47,8 +151,8 @@ fn pack_bool() {
 
 #[test]
 fn unpack_bool() {
-    assert_eq!(structure!("?").unpack(&[1]).unwrap(), (true, ));
-    assert_eq!(structure!("?").unpack(&[0]).unwrap(), (false, ));
+    assert_eq!(structure!("?").unpack([1]).unwrap(), (true, ));
+    assert_eq!(structure!("?").unpack([0]).unwrap(), (false, ));
 }
 
 #[test]
@@ -60,9 +164,9 @@ fn pack_big_endian() {
 
 #[test]
 fn unpack_big_endian() {
-    assert_eq!(structure!("I").unpack(&[0, 0, 0, 1]).unwrap(), (1, ));
-    assert_eq!(structure!(">I").unpack(&[0, 0, 0, 1]).unwrap(), (1, ));
-    assert_eq!(structure!("!I").unpack(&[0, 0, 0, 1]).unwrap(), (1, ));
+    assert_eq!(structure!("I").unpack([0, 0, 0, 1]).unwrap(), (1, ));
+    assert_eq!(structure!(">I").unpack([0, 0, 0, 1]).unwrap(), (1, ));
+    assert_eq!(structure!("!I").unpack([0, 0, 0, 1]).unwrap(), (1, ));
 }
 
 #[test]
@@ -72,7 +176,7 @@ fn pack_little_endian() {
 
 #[test]
 fn unpack_little_endian() {
-    assert_eq!(structure!("<I").unpack(&[1, 0, 0, 0]).unwrap(), (1, ));
+    assert_eq!(structure!("<I").unpack([1, 0, 0, 0]).unwrap(), (1, ));
 }
 
 #[test]
@@ -89,9 +193,9 @@ fn pack_native_endian() {
 fn unpack_native_endian() {
     let s = structure!("=I");
     if cfg!(target_endian = "little") {
-        assert_eq!(s.unpack(&[1, 0, 0, 0]).unwrap(), (1, ));
+        assert_eq!(s.unpack([1, 0, 0, 0]).unwrap(), (1, ));
     } else {
-        assert_eq!(s.unpack(&[0, 0, 0, 1]).unwrap(), (1, ));
+        assert_eq!(s.unpack([0, 0, 0, 1]).unwrap(), (1, ));
     }
 }
 
@@ -102,7 +206,7 @@ fn pack_repeat_count() {
 
 #[test]
 fn unpack_repeat_count() {
-    assert_eq!(structure!("2B2?").unpack(&[2, 3, 1, 0]).unwrap(), (2, 3, true, false));
+    assert_eq!(structure!("2B2?").unpack([2, 3, 1, 0]).unwrap(), (2, 3, true, false));
 }
 
 #[test]
@@ -116,11 +220,11 @@ fn pack_buffer() {
 
 #[test]
 fn unpack_buffer() {
-    assert_eq!(structure!("3s").unpack(&[1, 2, 3]).unwrap(), (vec![1, 2, 3], ));
-    assert_eq!(structure!("s").unpack(&[4]).unwrap(), (vec![4], ));
-    assert_eq!(structure!("0s").unpack(&[]).unwrap(), (vec![], ));
-    assert_eq!(structure!("2s").unpack(&[5, 6, 7]).unwrap_err().kind(), ErrorKind::InvalidInput);
-    assert_eq!(structure!("3s").unpack(&[8, 9]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("3s").unpack([1, 2, 3]).unwrap(), (vec![1, 2, 3], ));
+    assert_eq!(structure!("s").unpack([4]).unwrap(), (vec![4], ));
+    assert_eq!(structure!("0s").unpack([]).unwrap(), (vec![], ));
+    assert_eq!(structure!("2s").unpack([5, 6, 7]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("3s").unpack([8, 9]).unwrap_err().kind(), ErrorKind::InvalidInput);
 }
 
 #[test]
@@ -134,11 +238,11 @@ fn pack_fixed_buffer() {
 
 #[test]
 fn unpack_fixed_buffer() {
-    assert_eq!(structure!("3S").unpack(&[1, 2, 3]).unwrap(), (vec![1, 2, 3], ));
-    assert_eq!(structure!("S").unpack(&[4]).unwrap(), (vec![4], ));
-    assert_eq!(structure!("0S").unpack(&[]).unwrap(), (vec![], ));
-    assert_eq!(structure!("2S").unpack(&[5, 6, 7]).unwrap_err().kind(), ErrorKind::InvalidInput);
-    assert_eq!(structure!("3S").unpack(&[8, 9]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("3S").unpack([1, 2, 3]).unwrap(), (vec![1, 2, 3], ));
+    assert_eq!(structure!("S").unpack([4]).unwrap(), (vec![4], ));
+    assert_eq!(structure!("0S").unpack([]).unwrap(), (vec![], ));
+    assert_eq!(structure!("2S").unpack([5, 6, 7]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("3S").unpack([8, 9]).unwrap_err().kind(), ErrorKind::InvalidInput);
 }
 
 #[test]
@@ -147,16 +251,28 @@ fn pack_and_unpack_pointer() {
     let num2: u32 = 7;
     #[cfg(target_pointer_width = "64")]
     {
-        assert_eq!(structure!("=P").pack(unsafe { transmute::<&u32, *const c_void>(&num) }).unwrap(), unsafe { transmute::<&u32, [u8; 8]>(&num) });
+        assert_eq!(structure!("=P").pack(&num as *const u32 as *const c_void).unwrap(), unsafe { transmute::<&u32, [u8; 8]>(&num) });
     }
     #[cfg(target_pointer_width = "32")]
     {
-        assert_eq!(structure!("=P").pack(unsafe { transmute::<&u32, *const c_void>(&num) }).unwrap(), unsafe { transmute::<&u32, [u8; 4]>(&num) });
+        assert_eq!(structure!("=P").pack(&num as *const u32 as *const c_void).unwrap(), unsafe { transmute::<&u32, [u8; 4]>(&num) });
     }
-    let packed_pointers = structure!("=PP").pack(unsafe { transmute::<&u32, *const c_void>(&num) }, unsafe { transmute::<&u32, *const c_void>(&num2) }).unwrap();
+    let packed_pointers = structure!("=PP").pack(&num as *const u32 as *const c_void, &num2 as *const u32 as *const c_void).unwrap();
     let (p, p2) = structure!("=PP").unpack(packed_pointers).unwrap();
-    assert_eq!(p, unsafe { transmute::<&u32, *const c_void>(&num) });
-    assert_eq!(p2, unsafe { transmute::<&u32, *const c_void>(&num2) });
+    assert_eq!(p, &num as *const u32 as *const c_void);
+    assert_eq!(p2, &num2 as *const u32 as *const c_void);
+}
+
+// The pointer type of 'P' must not depend on the imports of the calling module
+mod pointer_without_c_void_import {
+    #[test]
+    fn pack_and_unpack_pointer() {
+        let num: u32 = 6;
+        let s = structure!("=P");
+        let packed = s.pack(&num as *const u32 as *const _).unwrap();
+        let (p, ) = s.unpack(packed).unwrap();
+        assert_eq!(p as *const u32, &num as *const u32);
+    }
 }
 
 #[test]
@@ -180,3 +296,796 @@ fn pack_and_unpack_padding() {
     let (b1, b2) = s.unpack(packed).unwrap();
     assert_eq!((b1, b2), (-1, -1));
 }
+
+#[test]
+fn pack_half_float() {
+    assert_eq!(structure!("e").pack(1.0).unwrap(), vec![0x3c, 0x00]);
+    assert_eq!(structure!("<e").pack(-2.0).unwrap(), vec![0x00, 0xc0]);
+    assert_eq!(structure!("e").pack(65504.0).unwrap(), vec![0x7b, 0xff]);
+    assert_eq!(structure!("e").pack(1e6).unwrap(), vec![0x7c, 0x00]);
+    assert_eq!(structure!("e").pack(5.960_464_5e-8).unwrap(), vec![0x00, 0x01]);
+    // Ties round to even
+    assert_eq!(structure!("e").pack(1.0 + 1.0 / 2048.0).unwrap(), vec![0x3c, 0x00]);
+    assert_eq!(structure!("e").pack(1.0 + 3.0 / 2048.0).unwrap(), vec![0x3c, 0x02]);
+    assert_eq!(structure!("e").pack(1.0 + 1.5 / 2048.0).unwrap(), vec![0x3c, 0x01]);
+}
+
+#[test]
+fn unpack_half_float() {
+    assert_eq!(structure!("e").unpack([0x3c, 0x00]).unwrap(), (1.0, ));
+    assert_eq!(structure!("<2e").unpack([0x00, 0xc0, 0x00, 0x35]).unwrap(), (-2.0, 0.3125));
+    assert_eq!(structure!("e").unpack([0x00, 0x01]).unwrap(), (5.960_464_5e-8, ));
+    assert_eq!(structure!("e").unpack([0x7c, 0x00]).unwrap(), (f32::INFINITY, ));
+    assert!(structure!("e").unpack([0x7e, 0x00]).unwrap().0.is_nan());
+    assert_eq!(structure!("eB").size(), 3);
+}
+
+#[test]
+fn pack_and_unpack_128_bit_integers() {
+    let s = structure!("oO");
+    let packed = s.pack(-2, 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10).unwrap();
+    let mut minus_two = [0xff; 16];
+    minus_two[15] = 0xfe;
+    assert_eq!(packed[..16], minus_two);
+    assert_eq!(packed[16..], [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16]);
+    assert_eq!(s.unpack(packed).unwrap(), (-2, 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10));
+    assert_eq!(s.size(), 32);
+    let s = structure!("<O");
+    assert_eq!(s.pack(1).unwrap(), vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(s.unpack([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x80]).unwrap(), (1 | 1 << 127, ));
+}
+
+#[test]
+fn pack_sized_integer() {
+    assert_eq!(structure!("u<24>").pack(0x010203).unwrap(), vec![1, 2, 3]);
+    assert_eq!(structure!("<u<24>").pack(0x010203).unwrap(), vec![3, 2, 1]);
+    assert_eq!(structure!("i<24>").pack(-2).unwrap(), vec![0xff, 0xff, 0xfe]);
+    assert_eq!(structure!("2u<40>").pack(1, 0xff_ffff_ffff).unwrap(), vec![0, 0, 0, 0, 1, 0xff, 0xff, 0xff, 0xff, 0xff]);
+    assert_eq!(structure!("i<72>").pack(-1).unwrap(), vec![0xff; 9]);
+    assert_eq!(structure!("u<24>").pack(0x0100_0000).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("i<24>").pack(0x80_0000).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("i<24>").pack(-0x80_0001).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("i<48>").pack(-0x8000_0000_0000).unwrap(), vec![0x80, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn unpack_sized_integer() {
+    assert_eq!(structure!("u<24>").unpack([1, 2, 3]).unwrap(), (0x010203u32, ));
+    assert_eq!(structure!("i<24>").unpack([0xff, 0xff, 0xfe]).unwrap(), (-2i32, ));
+    assert_eq!(structure!("<i<48>").unpack([0, 0, 0, 0, 0, 0x80]).unwrap(), (-0x8000_0000_0000i64, ));
+    assert_eq!(structure!("u<72>").unpack([1, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap(), (1u128 << 64, ));
+    assert_eq!(structure!("u<16>i<8>").unpack([1, 2, 0xff]).unwrap(), (0x0102u16, -1i8));
+    assert_eq!(structure!("u<24>2i<40>B").size(), 14);
+}
+
+#[test]
+fn pack_and_unpack_native_size() {
+    let s = structure!("=nN");
+    let packed = s.pack(-1, 2).unwrap();
+    let mut expected = (-1isize).to_ne_bytes().to_vec();
+    expected.extend_from_slice(&2usize.to_ne_bytes());
+    assert_eq!(packed, expected);
+    assert_eq!(s.unpack(packed).unwrap(), (-1isize, 2usize));
+    assert_eq!(s.size(), 2 * std::mem::size_of::<usize>());
+
+    let s = structure!("=B:n (n<=2)N");
+    assert_eq!(s.size_hint(), (1, Some(1 + 2 * std::mem::size_of::<usize>())));
+    let packed = s.pack(&[3, 4]).unwrap();
+    assert_eq!(packed.len(), 1 + 2 * std::mem::size_of::<usize>());
+    assert_eq!(s.unpack(&packed).unwrap(), (vec![3, 4],));
+}
+
+#[test]
+fn pack_and_unpack_char() {
+    let s = structure!("2cB");
+    assert_eq!(s.pack('h', 'i', 3).unwrap(), vec![b'h', b'i', 3]);
+    assert_eq!(s.unpack([b'h', b'i', 3]).unwrap(), ('h', 'i', 3));
+    assert_eq!(structure!("c").pack('é').unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("c").unpack([0x80]).unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn pack_pascal_string() {
+    assert_eq!(structure!("5p").pack(&[1, 2]).unwrap(), vec![2, 1, 2, 0, 0]);
+    assert_eq!(structure!("3p").pack(&[1, 2, 3, 4]).unwrap(), vec![2, 1, 2]);
+    assert_eq!(structure!("p").pack(&[1]).unwrap(), vec![0]);
+    assert_eq!(structure!("0p").pack(&[1]).unwrap(), vec![]);
+    let packed = structure!("300p").pack(&[7; 300]).unwrap();
+    assert_eq!(packed.len(), 300);
+    assert_eq!(packed[0], 255);
+    assert_eq!(packed[255], 7);
+    assert_eq!(packed[256], 0);
+}
+
+#[test]
+fn unpack_pascal_string() {
+    assert_eq!(structure!("5pB").unpack([2, 1, 2, 9, 9, 3]).unwrap(), (vec![1, 2], 3));
+    assert_eq!(structure!("3p").unpack([200, 1, 2]).unwrap(), (vec![1, 2], ));
+    assert_eq!(structure!("p").unpack([0]).unwrap(), (vec![], ));
+    assert_eq!(structure!("4p").unpack([0, 1]).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn pack_c_string() {
+    assert_eq!(structure!("zB").pack(b"ab", 3).unwrap(), vec![b'a', b'b', 0, 3]);
+    assert_eq!(structure!("z").pack(b"").unwrap(), vec![0]);
+    assert_eq!(structure!("z").pack(b"a\0b").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("4z").pack(b"ab").unwrap(), vec![b'a', b'b', 0, 0]);
+    assert_eq!(structure!("4z").pack(b"abcd").unwrap(), vec![b'a', b'b', b'c', b'd']);
+    assert_eq!(structure!("4z").pack(b"abcde").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(structure!("4z").pack(b"a\0").unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn unpack_c_string() {
+    let s = structure!("zzB");
+    assert_eq!(s.size(), 3);
+    assert_eq!(s.unpack(b"ab\0\0\x03").unwrap(), (b"ab".to_vec(), vec![], 3));
+    assert_eq!(s.unpack(b"ab\0\0\x03\x04").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(s.unpack(b"ab").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(s.unpack(b"abcd").unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    let mut rdr = Cursor::new(b"x\0y\0\x05rest");
+    assert_eq!(s.unpack_from(&mut rdr).unwrap(), (b"x".to_vec(), b"y".to_vec(), 5));
+    assert_eq!(rdr.position(), 5);
+    assert_eq!(structure!("4z").unpack(b"a\0b\0").unwrap(), (b"a".to_vec(), ));
+    assert_eq!(structure!("4z").unpack(b"abcd").unwrap(), (b"abcd".to_vec(), ));
+    assert_eq!(structure!("4z").size(), 4);
+}
+
+#[test]
+fn pack_length_prefixed_buffer() {
+    assert_eq!(structure!("s<H>").pack(&[1, 2, 3]).unwrap(), vec![0, 3, 1, 2, 3]);
+    assert_eq!(structure!("<s<I>").pack(&[1]).unwrap(), vec![1, 0, 0, 0, 1]);
+    assert_eq!(structure!("s<B>s<B>").pack(&[], &[9]).unwrap(), vec![0, 1, 9]);
+    assert_eq!(structure!("s<B>").pack(&[0; 256]).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn unpack_length_prefixed_buffer() {
+    let s = structure!("Bs<H>B");
+    assert_eq!(s.size(), 4);
+    assert_eq!(s.unpack([7, 0, 2, 1, 2, 8]).unwrap(), (7, vec![1, 2], 8));
+    assert_eq!(s.unpack([7, 0, 0, 8]).unwrap(), (7, vec![], 8));
+    assert_eq!(s.unpack([7, 0, 2, 1, 2, 8, 9]).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(s.unpack([7, 0, 9, 1, 2, 8]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    let mut rdr = Cursor::new(vec![0, 0, 0, 0, 0, 0, 0, 2, 5, 6, 7]);
+    assert_eq!(structure!("s<Q>").unpack_from(&mut rdr).unwrap(), (vec![5, 6], ));
+    assert_eq!(rdr.position(), 10);
+    assert_eq!(structure!("s<Q>").unpack([0xff; 8]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn pack_varint() {
+    assert_eq!(structure!("v").pack(0).unwrap(), vec![0]);
+    assert_eq!(structure!("v").pack(624485).unwrap(), vec![0xe5, 0x8e, 0x26]);
+    assert_eq!(structure!("v").pack(u64::MAX).unwrap(), vec![0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    assert_eq!(structure!("V").pack(-123456).unwrap(), vec![0xc0, 0xbb, 0x78]);
+    assert_eq!(structure!("V").pack(63).unwrap(), vec![0x3f]);
+    assert_eq!(structure!("V").pack(64).unwrap(), vec![0xc0, 0x00]);
+    assert_eq!(structure!("V").pack(-64).unwrap(), vec![0x40]);
+    assert_eq!(structure!("V").pack(i64::MIN).unwrap(), vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f]);
+    assert_eq!(structure!("2w").pack(0x7f, 0x0fff_ffff).unwrap(), vec![0x7f, 0xff, 0xff, 0xff, 0x7f]);
+    assert_eq!(structure!("w").pack(0x2000).unwrap(), vec![0xc0, 0x00]);
+}
+
+#[test]
+fn unpack_varint() {
+    assert_eq!(structure!("vB").unpack([0xe5, 0x8e, 0x26, 7]).unwrap(), (624485, 7));
+    assert_eq!(structure!("v").unpack([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]).unwrap(), (u64::MAX, ));
+    assert_eq!(structure!("v").unpack([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x02]).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(structure!("v").unpack([0x80; 11]).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(structure!("v").unpack([0x80]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    assert_eq!(structure!("V").unpack([0xc0, 0xbb, 0x78]).unwrap(), (-123456, ));
+    assert_eq!(structure!("V").unpack([0x40]).unwrap(), (-64, ));
+    assert_eq!(structure!("V").unpack([0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f]).unwrap(), (i64::MIN, ));
+    assert_eq!(structure!("V").unpack([0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]).unwrap(), (i64::MAX, ));
+    assert_eq!(structure!("V").unpack([0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(structure!("2w").unpack([0x7f, 0xff, 0xff, 0xff, 0x7f]).unwrap(), (0x7f, 0x0fff_ffff));
+    assert_eq!(structure!("w").unpack([0x82, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(structure!("w").unpack([0x81, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]).unwrap(), (u64::MAX, ));
+}
+
+#[test]
+fn size_hint() {
+    assert_eq!(structure!("IB").size_hint(), (5, Some(5)));
+    assert_eq!(structure!("Iv2w").size_hint(), (7, Some(34)));
+    assert_eq!(structure!("s<B>").size_hint(), (1, Some(256)));
+    assert_eq!(structure!("Bz").size_hint(), (2, None));
+}
+
+#[test]
+fn pack_and_unpack_zigzag_varint() {
+    let s = structure!("4k");
+    let packed = s.pack(0, -1, 1, -2).unwrap();
+    assert_eq!(packed, vec![0, 1, 2, 3]);
+    assert_eq!(s.unpack(packed).unwrap(), (0, -1, 1, -2));
+    assert_eq!(structure!("k").pack(i32::MIN).unwrap(), vec![0xff, 0xff, 0xff, 0xff, 0x0f]);
+    assert_eq!(structure!("k").unpack([0xfe, 0xff, 0xff, 0xff, 0x0f]).unwrap(), (i32::MAX, ));
+    assert_eq!(structure!("k").unpack([0x80, 0x80, 0x80, 0x80, 0x10]).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(structure!("K").pack(-64).unwrap(), vec![0x7f]);
+    assert_eq!(structure!("K").pack(i64::MIN).unwrap(), vec![0xff; 9].into_iter().chain(vec![0x01]).collect::<Vec<u8>>());
+    assert_eq!(structure!("K").unpack([0x80, 0x01]).unwrap(), (64, ));
+    assert_eq!(structure!("kK").size_hint(), (2, Some(15)));
+}
+
+#[test]
+fn pack_literal() {
+    assert_eq!(structure!("<I=0xCAFEBABE H").pack(1).unwrap(), vec![0xbe, 0xba, 0xfe, 0xca, 1, 0]);
+    assert_eq!(structure!("'\x7fELF' B").pack(2).unwrap(), vec![0x7f, b'E', b'L', b'F', 2]);
+    assert_eq!(structure!("B 2b=-1 u<24>=0x10203").pack(5).unwrap(), vec![5, 0xff, 0xff, 1, 2, 3]);
+    assert_eq!(structure!("'a\'\\\0' B=0b1_0").pack().unwrap(), vec![b'a', b'\'', b'\\', 0, 2]);
+}
+
+#[test]
+fn unpack_literal() {
+    use structure::error::{Literal, LiteralMismatch};
+
+    let s = structure!("<I=0xCAFEBABE H");
+    assert_eq!(s.size(), 6);
+    assert_eq!(s.unpack([0xbe, 0xba, 0xfe, 0xca, 1, 0]).unwrap(), (1, ));
+    let err = s.unpack([0xbe, 0xba, 0xfe, 0xcb, 1, 0]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(*err.get_ref().unwrap().downcast_ref::<LiteralMismatch>().unwrap(), LiteralMismatch {
+        offset: 0,
+        expected: Literal::Unsigned(0xcafebabe),
+        actual: Literal::Unsigned(0xcbfebabe),
+    });
+
+    let s = structure!("B 'ELF' b=-2");
+    assert_eq!(s.unpack(b"\x01ELF\xfe").unwrap(), (1, ));
+    let err = s.unpack(b"\x01ELG\xfe").unwrap_err();
+    assert_eq!(*err.get_ref().unwrap().downcast_ref::<LiteralMismatch>().unwrap(), LiteralMismatch {
+        offset: 1,
+        expected: Literal::Bytes(b"ELF".to_vec()),
+        actual: Literal::Bytes(b"ELG".to_vec()),
+    });
+    let err = s.unpack(b"\x01ELF\xff").unwrap_err();
+    assert_eq!(*err.get_ref().unwrap().downcast_ref::<LiteralMismatch>().unwrap(), LiteralMismatch {
+        offset: 4,
+        expected: Literal::Signed(-2),
+        actual: Literal::Signed(-1),
+    });
+    assert_eq!(err.to_string(), "Literal field does not match the format at offset 4 (expected: -2, actual: -1)");
+}
+
+#[test]
+fn pack_checksum() {
+    assert_eq!(structure!("9S#<crc32>").pack(b"123456789").unwrap()[9..], [0xcb, 0xf4, 0x39, 0x26]);
+    assert_eq!(structure!("<9S#<crc32>").pack(b"123456789").unwrap()[9..], [0x26, 0x39, 0xf4, 0xcb]);
+    assert_eq!(structure!("9S#<crc16>").pack(b"123456789").unwrap()[9..], [0x29, 0xb1]);
+    assert_eq!(structure!("3B#<xor8 1>").pack(1, 2, 4).unwrap(), vec![1, 2, 4, 6]);
+    // The checksum may come before the bytes it covers
+    assert_eq!(structure!("#<xor8 0..>3B").pack(1, 2, 4).unwrap(), vec![7, 1, 2, 4]);
+    assert_eq!(structure!("#<xor8 1..3>3B").pack(1, 2, 4).unwrap(), vec![3, 1, 2, 4]);
+    assert_eq!(structure!("B#<xor8 0..5>").pack(1).unwrap_err().kind(), ErrorKind::InvalidInput);
+    let ipv4 = structure!("!BBHHHBB#<internet 0..>4S4S");
+    let packed = ipv4.pack(0x45, 0, 0x73, 0, 0x4000, 0x40, 0x11, &[192, 168, 0, 1], &[192, 168, 0, 199]).unwrap();
+    assert_eq!(packed, vec![0x45, 0, 0, 0x73, 0, 0, 0x40, 0, 0x40, 0x11, 0xb8, 0x61, 192, 168, 0, 1, 192, 168, 0, 199]);
+}
+
+#[test]
+fn unpack_checksum() {
+    use structure::error::{ChecksumAlgorithm, ChecksumMismatch};
+
+    let s = structure!("<3H#<internet 0..>B");
+    assert_eq!(s.size(), 9);
+    let packed = s.pack(0x1234, 0xabcd, 0xffff, 7).unwrap();
+    assert_eq!(s.unpack(&packed).unwrap(), (0x1234, 0xabcd, 0xffff, 7));
+    let mut corrupted = packed.clone();
+    corrupted[8] = 8;
+    let err = s.unpack(&corrupted).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let mismatch = err.get_ref().unwrap().downcast_ref::<ChecksumMismatch>().unwrap();
+    assert_eq!(mismatch.offset, 6);
+    assert_eq!(mismatch.algorithm, ChecksumAlgorithm::Internet);
+    assert_eq!(mismatch.actual, u32::from(packed[6]) | u32::from(packed[7]) << 8);
+
+    // A PNG chunk, whose CRC covers the type and the data
+    let chunk = structure!("I=0 'IEND' #<crc32 4>");
+    assert_eq!(chunk.pack().unwrap(), b"\0\0\0\0IEND\xae\x42\x60\x82");
+    assert_eq!(chunk.unpack(b"\0\0\0\0IEND\xae\x42\x60\x82").unwrap(), ());
+    let err = chunk.unpack(b"\0\0\0\0IEND\xae\x42\x60\x83").unwrap_err();
+    assert_eq!(*err.get_ref().unwrap().downcast_ref::<ChecksumMismatch>().unwrap(), ChecksumMismatch {
+        offset: 8,
+        algorithm: ChecksumAlgorithm::Crc32,
+        expected: 0xae426082,
+        actual: 0xae426083,
+    });
+
+    // Both checksums cover each other, so the first one is computed while the second is zero
+    let s = structure!("#<xor8 0..>#<crc16 0..>B");
+    let packed = s.pack(5).unwrap();
+    assert_eq!(packed[0], 5);
+    assert_eq!(s.unpack(&packed).unwrap(), (5, ));
+    let mut rdr = Cursor::new(packed);
+    assert_eq!(s.unpack_from(&mut rdr).unwrap(), (5, ));
+}
+
+#[test]
+fn pack_counted_fields() {
+    let s = structure!("<H:count (count)I");
+    assert_eq!(s.size(), 2);
+    assert_eq!(s.size_hint(), (2, None));
+    assert_eq!(s.pack(&[1, 2]).unwrap(), b"\x02\x00\x01\x00\x00\x00\x02\x00\x00\x00");
+    assert_eq!(s.pack(&[]).unwrap(), b"\x00\x00");
+
+    let s = structure!("B:len (len<=4)s (len)B");
+    assert_eq!(s.size_hint(), (1, None));
+    assert_eq!(s.pack(b"ab", &[1, 2]).unwrap(), b"\x02ab\x01\x02");
+    let err = s.pack(b"ab", &[1]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = s.pack(b"abcde", &[1, 2, 3, 4, 5]).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let s = structure!("B:len (len)S");
+    assert!(s.pack(&[0; 255]).is_ok());
+    assert_eq!(s.pack(&[0; 256]).unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn unpack_counted_fields() {
+    let s = structure!("<H:count (count)I");
+    assert_eq!(s.unpack(b"\x02\x00\x01\x00\x00\x00\x02\x00\x00\x00").unwrap(), (vec![1, 2],));
+    assert_eq!(s.unpack(b"\x00\x00").unwrap(), (vec![],));
+    let err = s.unpack(b"\x02\x00\x01\x00\x00\x00").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    let s = structure!("!I v:len (len)? (len<=16)s");
+    assert_eq!(s.unpack(b"\x00\x00\x00\x07\x02\x01\x00hi").unwrap(), (7, vec![true, false], b"hi".to_vec()));
+    let err = s.unpack(b"\x00\x00\x00\x07\x02\x01").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    // A huge count is rejected before it is allocated
+    let s = structure!("v:len (len<=16)s");
+    assert_eq!(s.size_hint(), (1, Some(26)));
+    let err = s.unpack(b"\x80\x80\x80\x80\x01").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn pack_conditional_fields() {
+    let s = structure!("<B:flags [flags&0x80]H [flags&0x40]3s I");
+    assert_eq!(s.size(), 5);
+    assert_eq!(s.size_hint(), (5, Some(10)));
+    assert_eq!(s.pack(0xc0, Some(1), Some(b"ab"), 2).unwrap(), b"\xc0\x01\x00ab\x00\x02\x00\x00\x00");
+    assert_eq!(s.pack(0x40, None, Some(b"abc"), 2).unwrap(), b"\x40abc\x02\x00\x00\x00");
+    assert_eq!(s.pack(0, None, None, 2).unwrap(), b"\x00\x02\x00\x00\x00");
+    let err = s.pack(0x80, None, None, 2).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = s.pack(0, Some(1), None, 2).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    // An optional array, whose count is zero when it is absent
+    let s = structure!("?:present B:count [present](count)H");
+    assert_eq!(s.pack(true, Some(&[1, 2])).unwrap(), b"\x01\x02\x00\x01\x00\x02");
+    assert_eq!(s.pack(false, None).unwrap(), b"\x00\x00");
+}
+
+#[test]
+fn unpack_conditional_fields() {
+    let s = structure!("<B:flags [flags&0x80]H [flags&0x40]3s I");
+    assert_eq!(s.unpack(b"\xc0\x01\x00abc\x02\x00\x00\x00").unwrap(), (0xc0, Some(1), Some(b"abc".to_vec()), 2));
+    assert_eq!(s.unpack(b"\x01\x02\x00\x00\x00").unwrap(), (1, None, None, 2));
+    let err = s.unpack(b"\x80\x02\x00\x00\x00").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    let s = structure!("!v:length [length]z");
+    assert_eq!(s.unpack(b"\x05hello\x00").unwrap(), (5, Some(b"hello".to_vec())));
+    assert_eq!(s.unpack(b"\x00").unwrap(), (0, None));
+}
+
+#[test]
+fn pack_enum() {
+    assert_eq!(Message::Ping(7).pack().unwrap(), b"\x01\x07\x00\x00\x00");
+    assert_eq!(Message::Data(b"hi".to_vec(), true).pack().unwrap(), b"\x02\x02\x00hi\x01");
+    assert_eq!(Message::Big(7).pack().unwrap(), b"\x03\x00\x00\x00\x07");
+    assert_eq!(Message::Quit.pack().unwrap(), b"\x04");
+    assert_eq!(Message::Quit.tag(), 4);
+    let mut wtr = vec![];
+    Message::Ping(1).pack_into(&mut wtr).unwrap();
+    Message::Quit.pack_into(&mut wtr).unwrap();
+    assert_eq!(wtr, b"\x01\x01\x00\x00\x00\x04");
+}
+
+#[test]
+fn unpack_enum() {
+    use structure::error::UnknownTag;
+
+    assert_eq!(Message::unpack(b"\x01\x07\x00\x00\x00").unwrap(), Message::Ping(7));
+    assert_eq!(Message::unpack(b"\x02\x02\x00hi\x01").unwrap(), Message::Data(b"hi".to_vec(), true));
+    assert_eq!(Message::unpack(b"\x04").unwrap(), Message::Quit);
+    let mut rdr = Cursor::new(b"\x01\x01\x00\x00\x00\x04");
+    assert_eq!(Message::unpack_from(&mut rdr).unwrap(), Message::Ping(1));
+    assert_eq!(Message::unpack_from(&mut rdr).unwrap(), Message::Quit);
+
+    let err = Message::unpack(b"\x05").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(*err.get_ref().unwrap().downcast_ref::<UnknownTag>().unwrap(), UnknownTag { name: "Message", tag: 5 });
+    assert_eq!(Message::unpack(b"\x04\x00").unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(Message::unpack(b"\x01\x07").unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn pack_and_unpack_rest() {
+    let s = structure!("!HB*");
+    assert_eq!(s.size(), 3);
+    assert_eq!(s.size_hint(), (3, None));
+    assert_eq!(s.pack(1, 2, b"payload").unwrap(), b"\x00\x01\x02payload");
+    assert_eq!(s.pack(1, 2, b"").unwrap(), b"\x00\x01\x02");
+    assert_eq!(s.unpack(b"\x00\x01\x02payload").unwrap(), (1, 2, b"payload".to_vec()));
+    assert_eq!(s.unpack(b"\x00\x01\x02").unwrap(), (1, 2, vec![]));
+    assert_eq!(s.unpack(b"\x00\x01").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let mut rdr = Cursor::new(b"\x00\x01\x02abc");
+    assert_eq!(s.unpack_from(&mut rdr).unwrap(), (1, 2, b"abc".to_vec()));
+    assert_eq!(rdr.position(), 6);
+}
+
+#[test]
+fn pack_terminated_array() {
+    let s = structure!("<B ~(HB) B");
+    assert_eq!(s.size(), 5);
+    assert_eq!(s.size_hint(), (5, None));
+    assert_eq!(s.pack(1, &[(2, 3), (4, 5)], 6).unwrap(), b"\x01\x02\x00\x03\x04\x00\x05\x00\x00\x00\x06");
+    assert_eq!(s.pack(1, &[], 6).unwrap(), b"\x01\x00\x00\x00\x06");
+    // An entry cannot be the sentinel
+    assert_eq!(s.pack(1, &[(0, 0)], 6).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let s = structure!(">~(H)=0xFFFF<=2");
+    assert_eq!(s.size_hint(), (2, Some(6)));
+    assert_eq!(s.pack(&[0, 1]).unwrap(), b"\x00\x00\x00\x01\xff\xff");
+    assert_eq!(s.pack(&[0, 1, 2]).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let s = structure!("~(4S)='END!'");
+    assert_eq!(s.pack(&[b"abcd"]).unwrap(), b"abcdEND!");
+}
+
+#[test]
+fn unpack_terminated_array() {
+    let s = structure!("<B ~(HB) B");
+    assert_eq!(s.unpack(b"\x01\x02\x00\x03\x04\x00\x05\x00\x00\x00\x06").unwrap(), (1, vec![(2, 3), (4, 5)], 6));
+    assert_eq!(s.unpack(b"\x01\x00\x00\x00\x06").unwrap(), (1, vec![], 6));
+    let err = s.unpack(b"\x01\x02\x00\x03\x04\x00\x05").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+    let s = structure!(">~(H)=0xFFFF<=2");
+    assert_eq!(s.unpack(b"\x00\x00\x00\x01\xff\xff").unwrap(), (vec![0, 1],));
+    let err = s.unpack(b"\x00\x00\x00\x01\x00\x02\xff\xff").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+    let s = structure!("~(4S)='END!'");
+    assert_eq!(s.unpack(b"abcdEND!").unwrap(), (vec![b"abcd".to_vec()],));
+}
+
+#[test]
+fn pack_bitfields() {
+    let s = structure!("!H{3:flags 13:offset} B{lsb 1:a 1:b 6:c}");
+    assert_eq!(s.size(), 3);
+    assert_eq!(s.pack(0b010, 0x1234, true, false, 5).unwrap(), b"\x52\x34\x15");
+    let err = s.pack(8, 0, false, false, 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(s.pack(0, 0x2000, false, false, 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(s.pack(0, 0, false, false, 64).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    // Reserved bits, and bitfields of a sized integer
+    let s = structure!("<u<24>{12:x 4 8:y}");
+    assert_eq!(s.pack(0xabc, 0xde).unwrap(), b"\xde\xc0\xab");
+}
+
+#[test]
+fn unpack_bitfields() {
+    let s = structure!("!H{3:flags 13:offset} B{lsb 1:a 1:b 6:c}");
+    assert_eq!(s.unpack(b"\x52\x34\x15").unwrap(), (0b010, 0x1234, true, false, 5));
+
+    let s = structure!("<u<24>{12:x 4 8:y}");
+    assert_eq!(s.unpack(b"\xde\xcf\xab").unwrap(), (0xabc, 0xde));
+
+    // A field that is present only if a flag bit is set
+    let s = structure!("B{1:has_length 7:kind} [has_length]H");
+    assert_eq!(s.unpack(b"\x83\x00\x05").unwrap(), (true, 3, Some(5)));
+    assert_eq!(s.unpack(b"\x03").unwrap(), (false, 3, None));
+    assert_eq!(s.pack(true, 3, Some(5)).unwrap(), b"\x83\x00\x05");
+}
+
+#[test]
+fn pack_bit_level() {
+    // An H.264 NAL unit header
+    let s = structure!("%1 2 5");
+    assert_eq!(s.size(), 1);
+    assert_eq!(s.size_in_bits(), 8);
+    assert_eq!(s.pack(false, 3, 5).unwrap(), b"\x65");
+    assert_eq!(s.pack(false, 4, 5).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let s = structure!("%i4 x3 u9 1");
+    assert_eq!(s.size(), 3);
+    assert_eq!(s.size_hint(), (3, Some(3)));
+    assert_eq!(s.pack(-2, 0x1ff, true).unwrap(), b"\xe1\xff\x80");
+    assert_eq!(s.pack(-9, 0, false).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let s = structure!("%<3 12 i7");
+    assert_eq!(s.size_in_bits(), 22);
+    assert_eq!(s.pack(0b101, 0xabc, -1).unwrap(), b"\xe5\xd5\x3f");
+}
+
+#[test]
+fn unpack_bit_level() {
+    let s = structure!("%1 2 5");
+    assert_eq!(s.unpack(b"\x65").unwrap(), (false, 3, 5));
+
+    let s = structure!("%i4 x3 u9 1");
+    assert_eq!(s.unpack(b"\xe1\xff\x80").unwrap(), (-2, 0x1ff, true));
+    assert_eq!(s.unpack(b"\xe1\xff").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let s = structure!("%<3 12 i7");
+    assert_eq!(s.unpack(b"\xe5\xd5\x3f").unwrap(), (0b101, 0xabc, -1));
+
+    let s = structure!("%64 i64");
+    let packed = s.pack(u64::MAX - 1, i64::MIN).unwrap();
+    assert_eq!(s.unpack(&packed).unwrap(), (u64::MAX - 1, i64::MIN));
+}
+
+#[test]
+fn pack_and_unpack_enum_field() {
+    use structure::error::InvalidDiscriminant;
+
+    let s = structure!("H 2B<Status>");
+    assert_eq!(s.size(), 4);
+    assert_eq!(s.pack(7, Status::Idle, Status::Failed).unwrap(), b"\x00\x07\x01\xff");
+    assert_eq!(s.unpack(b"\x00\x07\x01\xff").unwrap(), (7, Status::Idle, Status::Failed));
+    let err = s.unpack(b"\x00\x07\x02\x03").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(*err.get_ref().unwrap().downcast_ref::<InvalidDiscriminant>().unwrap(), InvalidDiscriminant {
+        offset: 3,
+        index: 2,
+        type_name: "Status",
+        value: 3,
+    });
+
+    let s = structure!("?:busy [busy]B<Status>");
+    assert_eq!(s.pack(true, Some(Status::Busy)).unwrap(), b"\x01\x02");
+    assert_eq!(s.unpack(b"\x01\x02").unwrap(), (true, Some(Status::Busy)));
+}
+
+#[test]
+fn pack_and_unpack_user_type() {
+    let s = structure!("B {Angle} H");
+    assert_eq!(s.size(), 6);
+    assert_eq!(s.pack(1, &Angle(-2), 3).unwrap(), b"\x01\xff\xff\xfe\x00\x03");
+    assert_eq!(s.unpack(b"\x01\xff\xff\xfe\x00\x03").unwrap(), (1, Angle(-2), 3));
+    // Braces right after an integer hold a user-defined type too, unless they hold bitfields
+    let s = structure!("I{Angle}B{4:high 4:low}");
+    assert_eq!(s.pack(1, &Angle(2), 3, 4).unwrap(), b"\x00\x00\x00\x01\x00\x00\x02\x34");
+    assert_eq!(s.unpack(b"\x00\x00\x00\x01\x00\x00\x02\x34").unwrap(), (1, Angle(2), 3, 4));
+    assert!(s.unpack(b"\x01\xff\xff\xfe\x00").is_err());
+
+    let s = structure!("<2{Angle}");
+    assert_eq!(s.size(), 6);
+    assert_eq!(s.pack(&Angle(1), &Angle(0x10203)).unwrap(), b"\x01\x00\x00\x03\x02\x01");
+    assert_eq!(s.unpack(b"\x01\x00\x00\x03\x02\x01").unwrap(), (Angle(1), Angle(0x10203)));
+
+    let s = structure!("B:n (n<=4){Angle} ?:flag [flag]{Angle}");
+    assert_eq!(s.size_hint(), (2, Some(17)));
+    let angles = [Angle(1), Angle(2)];
+    let packed = s.pack(&angles, true, Some(&Angle(3))).unwrap();
+    assert_eq!(packed, b"\x02\x00\x00\x01\x00\x00\x02\x01\x00\x00\x03");
+    assert_eq!(s.unpack(&packed).unwrap(), (angles.to_vec(), true, Some(Angle(3))));
+
+    let message = Message::Turn(Angle(0x10203));
+    assert_eq!(message.pack().unwrap(), b"\x06\x03\x02\x01");
+    assert_eq!(Message::unpack(b"\x06\x03\x02\x01").unwrap(), message);
+}
+
+#[test]
+fn pack_and_unpack_user_type_named_t() {
+    #[derive(Debug, PartialEq)]
+    struct T(u8);
+
+    impl structure::format::Format for T {
+        const SIZE: usize = 1;
+
+        fn encode<E: ByteOrder, W: std::io::Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+            wtr.write_u8(self.0)
+        }
+
+        fn decode<E: ByteOrder, R: std::io::Read>(rdr: &mut R) -> std::io::Result<T> {
+            rdr.read_u8().map(T)
+        }
+    }
+
+    let s = structure!("{T} B@{T}");
+    assert_eq!(s.pack(&T(1), 2).unwrap(), b"\x01\x02");
+    assert_eq!(s.unpack(b"\x01\x02").unwrap(), (T(1), 2));
+    let mut rdr = std::io::Cursor::new(b"\x01\x02\x03".to_vec());
+    assert_eq!(s.unpack_from_seek(&mut rdr, 0).unwrap(), (T(1), T(3)));
+}
+
+#[test]
+fn pack_and_unpack_nested_layout() {
+    let header = Header(0x102, 3);
+    assert_eq!(header.pack().unwrap(), b"\x02\x01\x03s");
+    assert_eq!(Header::unpack(b"\x02\x01\x03s").unwrap(), header);
+    assert!(Header::unpack(b"\x02\x01\x03t").is_err());
+
+    let s = structure!("{Header} 2I");
+    assert_eq!(s.size(), 12);
+    let packed = s.pack(&header, 4, 5).unwrap();
+    assert_eq!(packed, b"\x02\x01\x03s\x00\x00\x00\x04\x00\x00\x00\x05");
+    assert_eq!(s.unpack(&packed).unwrap(), (header.clone(), 4, 5));
+
+    let s = structure!("B:n (n<=2){Header}");
+    assert_eq!(s.size_hint(), (1, Some(9)));
+    let headers = vec![Header(1, 2), Header(3, 4)];
+    let packed = s.pack(&headers).unwrap();
+    assert_eq!(packed, b"\x02\x01\x00\x02s\x03\x00\x04s");
+    assert_eq!(s.unpack(&packed).unwrap(), (headers,));
+}
+
+#[test]
+fn pack_and_unpack_layouts_from_another_module() {
+    use records::{Point, Segment};
+
+    let segment = Segment(Point(1, -1), Point(2, 3));
+    assert_eq!(segment.pack().unwrap(), b"\x01\x00\xff\xff\x02\x00\x03\x00");
+    assert_eq!(Segment::unpack(b"\x01\x00\xff\xff\x02\x00\x03\x00").unwrap(), segment);
+
+    let s = structure!("B {Point}");
+    assert_eq!(s.pack(7, &Point(1, 2)).unwrap(), b"\x07\x01\x00\x02\x00");
+    assert_eq!(s.unpack(b"\x07\x01\x00\x02\x00").unwrap(), (7, Point(1, 2)));
+}
+
+#[test]
+fn pack_and_unpack_alignment() {
+    let s = structure!("B x<4> I 3B x<8>");
+    assert_eq!(s.size(), 16);
+    let packed = s.pack(1, 2, 3, 4, 5).unwrap();
+    assert_eq!(packed, b"\x01\x00\x00\x00\x00\x00\x00\x02\x03\x04\x05\x00\x00\x00\x00\x00");
+    assert_eq!(s.unpack(&packed).unwrap(), (1, 2, 3, 4, 5));
+
+    let s = structure!("B:n (n)s x<4> H");
+    assert_eq!(s.size_hint(), (3, None));
+    assert_eq!(s.pack(b"abc", 7).unwrap(), b"\x03abc\x00\x07");
+    assert_eq!(s.pack(b"ab", 7).unwrap(), b"\x02ab\x00\x00\x07");
+    assert_eq!(s.unpack(b"\x02ab\x00\x00\x07").unwrap(), (b"ab".to_vec(), 7));
+    assert_eq!(s.unpack(b"\x02ab\x00\x07").unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+    let s = structure!("{Angle} x<4>");
+    assert_eq!(s.size_hint(), (3, Some(6)));
+    assert_eq!(s.pack(&Angle(1)).unwrap(), b"\x00\x00\x01\x00");
+    assert_eq!(s.unpack(b"\x00\x00\x01\x00").unwrap(), (Angle(1),));
+}
+
+#[test]
+fn unpack_offsets_with_seek() {
+    let s = structure!("B I@{Header} H@{Angle}");
+    let mut buf = b"\x01\x00\x00\x00\x04\x00\x0b\x02\x01\x03sxxx\xff\xff\xfe".to_vec();
+    assert_eq!(s.unpack(&buf[..7]).unwrap(), (1, 4, 11));
+    let mut rdr = Cursor::new(&buf);
+    assert_eq!(s.unpack_from_seek(&mut rdr, 3).unwrap(), (1, Header(0x102, 3), Angle(-2)));
+    assert_eq!(rdr.position(), 7);
+
+    // Relative to a record that starts after other data
+    buf.splice(0..0, b"ab".iter().cloned());
+    let mut rdr = Cursor::new(&buf);
+    rdr.set_position(2);
+    assert_eq!(s.unpack_from_seek(&mut rdr, 5).unwrap(), (1, Header(0x102, 3), Angle(-2)));
+    assert_eq!(rdr.position(), 9);
+
+    let mut rdr = Cursor::new(b"\x01\xff\xff\xff\xff\x00\x00");
+    assert_eq!(s.unpack_from_seek(&mut rdr, u64::MAX).unwrap_err().kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn pack_and_unpack_sized_record() {
+    let s = structure!("{Header}<B> H");
+    assert_eq!(s.size_hint(), (7, Some(258)));
+    let packed = s.pack(&Header(1, 2), 3).unwrap();
+    assert_eq!(packed, b"\x04\x01\x00\x02s\x00\x03");
+    assert_eq!(s.unpack(&packed).unwrap(), (Header(1, 2), 3));
+
+    // A newer record with trailing fields that are skipped
+    assert_eq!(s.unpack(b"\x06\x01\x00\x02sxy\x00\x03").unwrap(), (Header(1, 2), 3));
+    let err = s.unpack(b"\x03\x01\x00\x02s\x00\x03").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert_eq!(s.unpack(b"\x09\x01\x00\x02sxy\x00\x03").unwrap_err().kind(), ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn pack_and_unpack_with_byte_order() {
+    let s = structure!("H {Angle} B:n (n)I");
+    let packed = s.pack(1, &Angle(2), &[3]).unwrap();
+    assert_eq!(packed, b"\x00\x01\x00\x00\x02\x01\x00\x00\x00\x03");
+    assert_eq!(s.pack_with::<BigEndian>(1, &Angle(2), &[3]).unwrap(), packed);
+    let packed = s.pack_with::<LittleEndian>(1, &Angle(2), &[3]).unwrap();
+    assert_eq!(packed, b"\x01\x00\x02\x00\x00\x01\x03\x00\x00\x00");
+    assert_eq!(s.unpack_with::<LittleEndian, _>(&packed).unwrap(), (1, Angle(2), vec![3]));
+
+    let mut wtr = vec![];
+    s.pack_into_with::<LittleEndian, _>(&mut wtr, 1, &Angle(2), &[3]).unwrap();
+    assert_eq!(wtr, packed);
+    let mut rdr = Cursor::new(&wtr);
+    assert_eq!(s.unpack_from_with::<LittleEndian, _>(&mut rdr).unwrap(), (1, Angle(2), vec![3]));
+
+    let s = structure!("<H #<internet>");
+    assert_eq!(s.pack_with::<BigEndian>(0x1234).unwrap(), b"\x12\x34\xed\xcb");
+}
+
+#[test]
+fn pack_and_unpack_layouts_and_enums_with_byte_order() {
+    let pair = Pair(1, 2);
+    assert_eq!(pair.pack().unwrap(), b"\x00\x01\x02");
+    assert_eq!(pair.pack_with::<LittleEndian>().unwrap(), b"\x01\x00\x02");
+    assert_eq!(Pair::unpack_with::<LittleEndian, _>(b"\x01\x00\x02").unwrap(), pair);
+    let mut wtr = vec![];
+    pair.pack_into_with::<LittleEndian, _>(&mut wtr).unwrap();
+    assert_eq!(wtr, b"\x01\x00\x02");
+    assert_eq!(Pair::unpack_from_with::<LittleEndian, _>(&mut Cursor::new(&wtr)).unwrap(), pair);
+
+    // A nested layout without a byte order of its own takes the byte order of the outer format
+    let s = structure!("<{Pair} H");
+    assert_eq!(s.pack(&pair, 3).unwrap(), b"\x01\x00\x02\x03\x00");
+    assert_eq!(s.pack_with::<BigEndian>(&pair, 3).unwrap(), b"\x00\x01\x02\x00\x03");
+    assert_eq!(s.unpack(b"\x01\x00\x02\x03\x00").unwrap(), (Pair(1, 2), 3));
+    let s = structure!("<{Header}");
+    assert_eq!(s.pack_with::<BigEndian>(&Header(1, 2)).unwrap(), b"\x01\x00\x02s");
+
+    let message = Message::Ping(1);
+    assert_eq!(message.pack().unwrap(), b"\x01\x01\x00\x00\x00");
+    assert_eq!(message.pack_with::<BigEndian>().unwrap(), b"\x01\x00\x00\x00\x01");
+    assert_eq!(Message::unpack_with::<BigEndian, _>(b"\x01\x00\x00\x00\x01").unwrap(), message);
+    // A variant with its own byte order keeps it
+    let message = Message::Big(1);
+    assert_eq!(message.pack_with::<LittleEndian>().unwrap(), b"\x03\x00\x00\x00\x01");
+    assert_eq!(Message::unpack_with::<LittleEndian, _>(b"\x03\x00\x00\x00\x01").unwrap(), message);
+    let mut wtr = vec![];
+    Message::Quit.pack_into_with::<BigEndian, _>(&mut wtr).unwrap();
+    assert_eq!(wtr, b"\x04");
+    assert_eq!(Message::unpack_from_with::<BigEndian, _>(&mut Cursor::new(&wtr)).unwrap(), Message::Quit);
+
+    let s = structure!("B H@{Pair}");
+    let mut rdr = Cursor::new(b"\x07\x04\x00\xff\x01\x00\x02".to_vec());
+    assert_eq!(s.unpack_from_seek_with::<LittleEndian, _>(&mut rdr, 0).unwrap(), (7, Pair(1, 2)));
+    assert_eq!(rdr.position(), 3);
+}
+
+#[test]
+fn pack_and_unpack_with_byte_order_of_type_named_e() {
+    #[derive(Debug, Clone, PartialEq)]
+    struct E(u16);
+
+    impl structure::format::Format for E {
+        const SIZE: usize = 2;
+
+        fn encode<O: ByteOrder, W: std::io::Write>(&self, wtr: &mut W) -> std::io::Result<()> {
+            wtr.write_u16::<O>(self.0)
+        }
+
+        fn decode<O: ByteOrder, R: std::io::Read>(rdr: &mut R) -> std::io::Result<E> {
+            rdr.read_u16::<O>().map(E)
+        }
+    }
+
+    let s = structure!("{E} B");
+    assert_eq!(s.pack(&E(1), 2).unwrap(), b"\x00\x01\x02");
+    let packed = s.pack_with::<LittleEndian>(&E(1), 2).unwrap();
+    assert_eq!(packed, b"\x01\x00\x02");
+    assert_eq!(s.unpack_with::<LittleEndian, _>(&packed).unwrap(), (E(1), 2));
+}
+
+#[test]
+fn pack_and_unpack_owned_enum_fields() {
+    let framing = Framing(Kind::Framed, Kind::Plain, 2);
+    assert_eq!(framing.pack().unwrap(), b"\x01\x00\x00\x02");
+    assert_eq!(Framing::unpack(b"\x01\x00\x00\x02").unwrap(), framing);
+
+    let message = Message::Frame(true, Some(Kind::Framed));
+    assert_eq!(message.pack().unwrap(), b"\x07\x01\x01");
+    assert_eq!(Message::unpack(b"\x07\x01\x01").unwrap(), message);
+    assert_eq!(Message::unpack(b"\x07\x00").unwrap(), Message::Frame(false, None));
+
+    let s = structure!("~(B<Kind>)");
+    assert_eq!(s.pack(&[Kind::Framed, Kind::Framed]).unwrap(), b"\x01\x01\x00");
+    assert_eq!(s.unpack(b"\x01\x01\x00").unwrap(), (vec![Kind::Framed, Kind::Framed],));
+    let s = structure!("~(B B<Kind>)");
+    assert_eq!(s.pack(&[(1, Kind::Plain), (0, Kind::Framed)]).unwrap(), b"\x01\x00\x00\x01\x00\x00");
+    assert_eq!(s.unpack(b"\x01\x00\x00\x01\x00\x00").unwrap(), (vec![(1, Kind::Plain), (0, Kind::Framed)],));
+}